@@ -9,12 +9,16 @@
 use std::path::PathBuf;
 
 use maplit::hashmap;
+use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
 use serde_bser::value::Value;
 
 use crate::pdu::*;
 
 /// An expression term used to filter candidate files from query results.
+/// The server has no terms that match on file ownership; see
+/// `FileOwner` for filtering by owner on the client.
 #[derive(Serialize, Debug, Clone)]
 #[serde(into = "Value")]
 pub enum Expr {
@@ -53,10 +57,18 @@ pub enum Expr {
     /// <https://facebook.github.io/watchman/docs/expr/match.html>
     Match(MatchTerm),
 
+    /// Performs a case insensitive glob-style match against the file name
+    /// <https://facebook.github.io/watchman/docs/expr/match.html>
+    IMatch(MatchTerm),
+
     /// Performs an exact match against the file name.
     /// <https://facebook.github.io/watchman/docs/expr/name.html>
     Name(NameTerm),
 
+    /// Performs a case insensitive exact match against the file name.
+    /// <https://facebook.github.io/watchman/docs/expr/name.html>
+    IName(NameTerm),
+
     /// Use PCRE to match the filename.
     /// Note that this is an optional server feature and using this term
     /// on a server that doesn't support this feature will generate an
@@ -83,65 +95,60 @@ pub enum Expr {
 
     /// Evaluate as true if the file type exactly matches the specified type.
     FileType(FileType),
+
+    /// An expression that is passed through to the server verbatim.
+    /// Use this as an escape hatch to emit expression terms that are not
+    /// yet modelled by this crate.
+    /// This is also produced when deserializing an expression, such as the
+    /// expression of a trigger returned by `trigger-list`, which may
+    /// have been registered using the legacy terse syntax.  Preserving
+    /// the original value ensures that such filters are not dropped.
+    Raw(Value),
 }
 
-impl Into<Value> for Expr {
-    fn into(self) -> Value {
-        match self {
-            Self::True => "true".into(),
-            Self::False => "false".into(),
-            Self::Not(expr) => Value::Array(vec!["not".into(), (*expr).into()]),
-            Self::All(expr) => {
+/// Expressions are deserialized as `Expr::Raw`, as the server echoes
+/// back whatever syntax was used to register them, including the legacy
+/// terse forms, which don't have a one-to-one mapping to the other
+/// variants of `Expr`.
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Value::deserialize(deserializer).map(Expr::Raw)
+    }
+}
+
+impl From<Expr> for Value {
+    fn from(expr: Expr) -> Self {
+        match expr {
+            Expr::True => "true".into(),
+            Expr::False => "false".into(),
+            Expr::Not(expr) => Value::Array(vec!["not".into(), (*expr).into()]),
+            Expr::All(expr) => {
                 let mut expr: Vec<Value> = expr.into_iter().map(Into::into).collect();
                 expr.insert(0, "allof".into());
                 Value::Array(expr)
             }
-            Self::Any(expr) => {
+            Expr::Any(expr) => {
                 let mut expr: Vec<Value> = expr.into_iter().map(Into::into).collect();
                 expr.insert(0, "anyof".into());
                 Value::Array(expr)
             }
-            Self::DirName(term) => {
+            Expr::DirName(term) => {
                 let mut expr: Vec<Value> = vec!["dirname".into(), term.path.try_into().unwrap()];
                 if let Some(depth) = term.depth {
                     expr.push(depth.into_term("depth"));
                 }
                 expr.into()
             }
-            Self::Empty => "empty".into(),
-            Self::Exists => "exists".into(),
-            Self::Match(term) => vec![
-                "match".into(),
-                term.glob.into(),
-                if term.wholename {
-                    "wholename"
-                } else {
-                    "basename"
-                }
-                .into(),
-                Value::Object(hashmap! {
-                    "includedotfiles".to_string() => term.include_dot_files.into(),
-                    "noescape".to_string() => term.no_escape.into()
-                }),
-            ]
-            .into(),
-            Self::Name(term) => vec![
-                "name".into(),
-                Value::Array(
-                    term.paths
-                        .into_iter()
-                        .map(|p| p.try_into().unwrap())
-                        .collect(),
-                ),
-                if term.wholename {
-                    "wholename"
-                } else {
-                    "basename"
-                }
-                .into(),
-            ]
-            .into(),
-            Self::Pcre(term) => vec![
+            Expr::Empty => "empty".into(),
+            Expr::Exists => "exists".into(),
+            Expr::Match(term) => term.into_term("match"),
+            Expr::IMatch(term) => term.into_term("imatch"),
+            Expr::Name(term) => term.into_term("name"),
+            Expr::IName(term) => term.into_term("iname"),
+            Expr::Pcre(term) => vec![
                 "pcre".into(),
                 term.pattern.into(),
                 if term.wholename {
@@ -152,7 +159,7 @@ impl Into<Value> for Expr {
                 .into(),
             ]
             .into(),
-            Self::Since(term) => match term {
+            Expr::Since(term) => match term {
                 SinceTerm::ObservedClock(c) => {
                     vec!["since".into(), c.into(), "oclock".into()].into()
                 }
@@ -166,13 +173,133 @@ impl Into<Value> for Expr {
                     vec!["since".into(), c.to_string().into(), "ctime".into()].into()
                 }
             },
-            Self::Size(term) => term.into_term("size"),
-            Self::Suffix(term) => vec![
+            Expr::Size(term) => term.into_term("size"),
+            Expr::Suffix(term) => vec![
                 "suffix".into(),
                 Value::Array(term.into_iter().map(|p| p.try_into().unwrap()).collect()),
             ]
             .into(),
-            Self::FileType(term) => vec!["type".into(), term.to_string().into()].into(),
+            Expr::FileType(term) => vec!["type".into(), term.to_string().into()].into(),
+            Expr::Raw(value) => value,
+        }
+    }
+}
+
+impl Expr {
+    /// Construct a glob-style match term, selecting between the `match`
+    /// and `imatch` terms according to `case_sensitive`.
+    /// <https://facebook.github.io/watchman/docs/expr/match.html>
+    pub fn match_pattern(
+        pattern: impl Into<String>,
+        scope: MatchScope,
+        case_sensitive: bool,
+    ) -> Self {
+        let term = MatchTerm {
+            glob: pattern.into(),
+            wholename: scope == MatchScope::Wholename,
+            ..Default::default()
+        };
+        if case_sensitive {
+            Self::Match(term)
+        } else {
+            Self::IMatch(term)
+        }
+    }
+
+    /// Construct an exact name match term, selecting between the `name`
+    /// and `iname` terms according to `case_sensitive`.
+    /// <https://facebook.github.io/watchman/docs/expr/name.html>
+    pub fn name<P: Into<PathBuf>>(
+        names: impl IntoIterator<Item = P>,
+        scope: MatchScope,
+        case_sensitive: bool,
+    ) -> Self {
+        let term = NameTerm {
+            paths: names.into_iter().map(Into::into).collect(),
+            wholename: scope == MatchScope::Wholename,
+        };
+        if case_sensitive {
+            Self::Name(term)
+        } else {
+            Self::IName(term)
+        }
+    }
+
+    /// Construct a case insensitive exact name match term, which always
+    /// uses the `iname` term.
+    /// Prefer this when matching names on case insensitive filesystems,
+    /// such as the defaults on macOS and Windows, where the name that the
+    /// server reports may differ in case from the one you are looking for.
+    pub fn name_ci<P: Into<PathBuf>>(
+        names: impl IntoIterator<Item = P>,
+        scope: MatchScope,
+    ) -> Self {
+        Self::name(names, scope, false)
+    }
+
+    /// Construct a case sensitive exact name match term, which always
+    /// uses the `name` term.
+    /// Prefer this on case sensitive filesystems, such as those typically
+    /// used on Linux, where names differing only in case are distinct.
+    /// Note that the server uses case insensitive matching for `name` when
+    /// the watched filesystem is case insensitive, unless the query sets
+    /// `case_sensitive`.
+    pub fn name_cs<P: Into<PathBuf>>(
+        names: impl IntoIterator<Item = P>,
+        scope: MatchScope,
+    ) -> Self {
+        Self::name(names, scope, true)
+    }
+
+    /// Construct a term that matches files of any of the specified types.
+    /// The `type` term accepts only a single type, so this produces an
+    /// `anyof` term holding a `type` term for each of `types`.
+    /// <https://facebook.github.io/watchman/docs/expr/type.html>
+    pub fn file_types(types: impl IntoIterator<Item = FileType>) -> Self {
+        Self::Any(types.into_iter().map(Self::FileType).collect())
+    }
+
+    /// Construct a term that matches files whose name ends with any of
+    /// `suffixes`, or whose basename is exactly one of `names`, such as
+    /// the files with the `ts` suffix together with `tsconfig.json`.
+    /// This produces an `anyof` term holding a `suffix` term and a
+    /// case sensitive `name` term; note that the server always compares
+    /// suffixes case insensitively.
+    pub fn any_suffix_or_name<S: Into<PathBuf>, N: Into<PathBuf>>(
+        suffixes: impl IntoIterator<Item = S>,
+        names: impl IntoIterator<Item = N>,
+    ) -> Self {
+        Self::Any(vec![
+            Self::Suffix(suffixes.into_iter().map(Into::into).collect()),
+            Self::name(names, MatchScope::Basename, true),
+        ])
+    }
+}
+
+/// Specifies which portion of the file name is considered by
+/// the name matching terms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MatchScope {
+    /// Match against the basename portion of the filename
+    #[default]
+    Basename,
+    /// Match against the path relative to the root of the project
+    Wholename,
+}
+
+impl MatchScope {
+    fn from_wholename(wholename: bool) -> Self {
+        if wholename {
+            Self::Wholename
+        } else {
+            Self::Basename
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Basename => "basename",
+            Self::Wholename => "wholename",
         }
     }
 }
@@ -188,6 +315,22 @@ pub struct NameTerm {
     pub wholename: bool,
 }
 
+impl NameTerm {
+    fn into_term(self, term: &str) -> Value {
+        vec![
+            term.into(),
+            Value::Array(
+                self.paths
+                    .into_iter()
+                    .map(|p| p.try_into().unwrap())
+                    .collect(),
+            ),
+            MatchScope::from_wholename(self.wholename).as_str().into(),
+        ]
+        .into()
+    }
+}
+
 /// Match on the parent directory structure
 /// <https://facebook.github.io/watchman/docs/expr/dirname.html>
 #[derive(Clone, Debug)]
@@ -236,6 +379,21 @@ pub struct MatchTerm {
     pub no_escape: bool,
 }
 
+impl MatchTerm {
+    fn into_term(self, term: &str) -> Value {
+        vec![
+            term.into(),
+            self.glob.into(),
+            MatchScope::from_wholename(self.wholename).as_str().into(),
+            Value::Object(hashmap! {
+                "includedotfiles".to_string() => self.include_dot_files.into(),
+                "noescape".to_string() => self.no_escape.into()
+            }),
+        ]
+        .into()
+    }
+}
+
 /// Specifies a relational comparison with an integer value
 #[derive(Clone, Debug)]
 pub enum RelOp {
@@ -381,6 +539,80 @@ mod tests {
             .into()
         );
 
+        assert_eq!(
+            val(Expr::match_pattern("*.TXT", MatchScope::Basename, true)),
+            vec![
+                "match".into(),
+                "*.TXT".into(),
+                "basename".into(),
+                hashmap! {
+                    "includedotfiles".to_string() => Value::Bool(false),
+                    "noescape".to_string() => Value::Bool(false),
+                }
+                .into()
+            ]
+            .into()
+        );
+
+        assert_eq!(
+            val(Expr::match_pattern(
+                "src/*.TXT",
+                MatchScope::Wholename,
+                false
+            )),
+            vec![
+                "imatch".into(),
+                "src/*.TXT".into(),
+                "wholename".into(),
+                hashmap! {
+                    "includedotfiles".to_string() => Value::Bool(false),
+                    "noescape".to_string() => Value::Bool(false),
+                }
+                .into()
+            ]
+            .into()
+        );
+
+        assert_eq!(
+            val(Expr::name(["Makefile"], MatchScope::Basename, true)),
+            vec![
+                "name".into(),
+                vec![Value::ByteString("Makefile".into())].into(),
+                "basename".into()
+            ]
+            .into()
+        );
+
+        assert_eq!(
+            val(Expr::name(["src/Makefile"], MatchScope::Wholename, false)),
+            vec![
+                "iname".into(),
+                vec![Value::ByteString("src/Makefile".into())].into(),
+                "wholename".into()
+            ]
+            .into()
+        );
+
+        assert_eq!(
+            val(Expr::name_ci(["README.md"], MatchScope::Basename)),
+            vec![
+                "iname".into(),
+                vec![Value::ByteString("README.md".into())].into(),
+                "basename".into()
+            ]
+            .into()
+        );
+
+        assert_eq!(
+            val(Expr::name_cs(["docs/README.md"], MatchScope::Wholename)),
+            vec![
+                "name".into(),
+                vec![Value::ByteString("docs/README.md".into())].into(),
+                "wholename".into()
+            ]
+            .into()
+        );
+
         assert_eq!(
             val(Expr::Pcre(PcreTerm {
                 pattern: "foo$".into(),
@@ -393,6 +625,15 @@ mod tests {
             val(Expr::FileType(FileType::Regular)),
             vec!["type".into(), "f".into()].into()
         );
+        assert_eq!(
+            val(Expr::file_types([FileType::Regular, FileType::Symlink])),
+            vec![
+                "anyof".into(),
+                vec!["type".into(), "f".into()].into(),
+                vec!["type".into(), "l".into()].into(),
+            ]
+            .into()
+        );
 
         assert_eq!(
             val(Expr::Suffix(vec!["php".into(), "js".into()])),
@@ -407,9 +648,53 @@ mod tests {
             .into()
         );
 
+        assert_eq!(
+            val(Expr::any_suffix_or_name(["ts"], ["tsconfig.json"])),
+            vec![
+                "anyof".into(),
+                vec!["suffix".into(), vec![Value::ByteString("ts".into())].into()].into(),
+                vec![
+                    "name".into(),
+                    vec![Value::ByteString("tsconfig.json".into())].into(),
+                    "basename".into()
+                ]
+                .into(),
+            ]
+            .into()
+        );
+
         assert_eq!(
             val(Expr::Since(SinceTerm::ObservedClock(ClockSpec::null()))),
             vec!["since".into(), "c:0:0".into(), "oclock".into()].into()
         );
     }
+
+    #[test]
+    fn raw_custom_expr() {
+        let custom: Value = vec!["newterm".into(), "arg".into(), 3.into()].into();
+        let expr = Expr::All(vec![Expr::Exists, Expr::Raw(custom.clone())]);
+        let expected: Value = vec!["allof".into(), "exists".into(), custom.clone()].into();
+
+        let binary = serde_bser::ser::serialize(Vec::new(), &expr).unwrap();
+        let decoded: Value = serde_bser::from_slice(&binary).unwrap();
+        assert_eq!(decoded, expected);
+
+        let expr: Expr = serde_bser::from_slice(&binary).unwrap();
+        assert_eq!(val(expr), expected);
+    }
+
+    #[test]
+    fn raw_terse_expr() {
+        // A terse suffix term as registered by a legacy trigger
+        let terse: Value = vec![
+            "anyof".into(),
+            vec!["suffix".into(), "js".into()].into(),
+            vec!["match".into(), "*.c".into()].into(),
+        ]
+        .into();
+        let binary = serde_bser::ser::serialize(Vec::new(), &terse).unwrap();
+        let expr: Expr = serde_bser::from_slice(&binary).unwrap();
+        assert!(matches!(expr, Expr::Raw(_)));
+        assert_eq!(val(expr), terse);
+    }
 }
@@ -205,6 +205,30 @@ define_field!(
     "gid"
 );
 
+/// The owner of a file, as reported by the `uid` and `gid` fields.
+/// The server has no expression term that matches files by their owner,
+/// so selecting the files owned by a particular user must be done by
+/// the client: request the `uid` and `gid` fields and examine the
+/// `FileOwner` of each file in the results.
+/// This can also be used with `#[serde(flatten)]` in a hand written
+/// result struct.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FileOwner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl FileOwner {
+    /// Returns the owner described by the `uid` and `gid` fields of a
+    /// `query_result_type!` struct.
+    pub fn from_fields(uid: &OwnerUidField, gid: &OwnerGidField) -> Self {
+        Self {
+            uid: **uid,
+            gid: **gid,
+        }
+    }
+}
+
 define_field!(
     /// The field corresponding to the `ino` field.
     /// The ino field is the inode number expressed as an integer.
@@ -340,9 +364,15 @@ pub struct NameOnly {
     pub name: NameField,
 }
 
+impl NameOnly {
+    /// The list of fields to request from the server, for use when
+    /// constructing a query by hand rather than via `Client::query`.
+    pub const FIELDS: &'static [&'static str] = &["name"];
+}
+
 impl QueryFieldList for NameOnly {
     fn field_list() -> Vec<&'static str> {
-        vec!["name"]
+        Self::FIELDS.to_vec()
     }
 }
 
@@ -354,6 +384,27 @@ impl From<PathBuf> for NameOnly {
     }
 }
 
+/// Use the `NameAndType` struct when your desired field list in your
+/// query results consists of just the name and type fields.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NameAndType {
+    pub name: PathBuf,
+    #[serde(rename = "type")]
+    pub file_type: FileType,
+}
+
+impl NameAndType {
+    /// The list of fields to request from the server, for use when
+    /// constructing a query by hand rather than via `Client::query`.
+    pub const FIELDS: &'static [&'static str] = &["name", "type"];
+}
+
+impl QueryFieldList for NameAndType {
+    fn field_list() -> Vec<&'static str> {
+        Self::FIELDS.to_vec()
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct NoField {}
 
@@ -85,7 +85,9 @@ pub mod prelude {
     pub use crate::CanonicalPath;
     pub use crate::Client;
     pub use crate::Connector;
+    pub use crate::RequestDeadline;
     pub use crate::ResolvedRoot;
+    pub use crate::RootResolver;
 }
 
 use prelude::*;
@@ -139,6 +141,20 @@ pub enum Error {
         source: anyhow::Error,
     },
 
+    #[error(
+        "Timed out after {timeout:?} waiting for a response to the request with request_id {request_id:?}"
+    )]
+    Timeout {
+        request_id: Option<String>,
+        timeout: std::time::Duration,
+    },
+
+    #[error("Invalid query: {message}")]
+    InvalidQuery { message: String },
+
+    #[error("The deadline passed before a response to the request was received")]
+    DeadlineExceeded,
+
     #[error("Failed to connect to {endpoint}")]
     Connect {
         endpoint: PathBuf,
@@ -147,6 +163,106 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// Classifies a server reported error that occurred while executing
+    /// a query, returning `None` if the error is not one that we know
+    /// how to classify.
+    ///
+    /// The most common case worth handling is `QueryError::SyncTimeout`,
+    /// which is generally transient and can be retried with a longer
+    /// `sync_timeout`.  We recommend an exponential backoff, doubling the
+    /// timeout on each attempt and giving up after a small number of tries:
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use watchman_client::prelude::*;
+    /// use watchman_client::QueryError;
+    ///
+    /// async fn query_with_retry(
+    ///     client: &Client,
+    ///     resolved: &ResolvedRoot,
+    /// ) -> Result<QueryResult<NameOnly>, watchman_client::Error> {
+    ///     let mut timeout = Duration::from_secs(60);
+    ///     let mut attempts = 0;
+    ///     loop {
+    ///         let query = QueryRequestCommon {
+    ///             sync_timeout: timeout.into(),
+    ///             ..Default::default()
+    ///         };
+    ///         match client.query(resolved, query).await {
+    ///             Err(err) if attempts < 3 => match err.query_error() {
+    ///                 Some(QueryError::SyncTimeout { .. }) => {
+    ///                     attempts += 1;
+    ///                     timeout *= 2;
+    ///                 }
+    ///                 _ => return Err(err),
+    ///             },
+    ///             result => return result,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn query_error(&self) -> Option<QueryError> {
+        match self {
+            Self::WatchmanServerError { message, .. } | Self::WatchmanResponseError { message } => {
+                QueryError::from_message(message)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A classification of some of the errors that the server may
+/// report in response to a query.
+/// Use [Error::query_error](enum.Error.html#method.query_error) to
+/// obtain this from an `Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    /// The server did not observe the sync cookie within the
+    /// `sync_timeout` specified in the query.
+    /// `waited_ms` holds the timeout reported by the server, if it
+    /// could be parsed from the error message.
+    /// <https://facebook.github.io/watchman/docs/cookies.html>
+    SyncTimeout { waited_ms: Option<u64> },
+    /// The server doesn't recognize the named entry in the `fields` list
+    /// of the query, which is typically the case when the server is older
+    /// than the client and predates that field.
+    UnknownField { field: String },
+    /// The server doesn't recognize, or wasn't built with support for,
+    /// the named expression term.
+    UnknownExpressionTerm { term: String },
+}
+
+impl QueryError {
+    /// Attempt to classify the error message reported by the server.
+    pub fn from_message(message: &str) -> Option<Self> {
+        if message.contains("syncToNow: timed out waiting for") {
+            let waited_ms = message
+                .split_once(" within ")
+                .and_then(|(_, rest)| rest.split_once(" milliseconds"))
+                .and_then(|(ms, _)| ms.parse().ok());
+            return Some(Self::SyncTimeout { waited_ms });
+        }
+        if let Some(field) = quoted_after(message, "unknown field name ") {
+            return Some(Self::UnknownField { field });
+        }
+        if let Some(term) = quoted_after(message, "unknown expression term ")
+            .or_else(|| quoted_after(message, "unsupported expression term "))
+        {
+            return Some(Self::UnknownExpressionTerm { term });
+        }
+        None
+    }
+}
+
+/// Returns the single quoted string that immediately follows `prefix`
+/// in `message`.
+fn quoted_after(message: &str, prefix: &str) -> Option<String> {
+    let (_, rest) = message.split_once(prefix)?;
+    let (name, _) = rest.strip_prefix('\'')?.split_once('\'')?;
+    Some(name.to_string())
+}
+
 #[derive(Error, Debug)]
 enum TaskError {
     #[error("IO Error: {0}")]
@@ -224,7 +340,7 @@ impl Connector {
                 .unwrap_or_else(|| Path::new("watchman"));
 
             let mut cmd = Command::new(watchman_path);
-            cmd.args(&["--output-encoding", "bser-v2", "get-sockname"]);
+            cmd.args(["--output-encoding", "bser-v2", "get-sockname"]);
 
             #[cfg(windows)]
             cmd.creation_flags(winapi::um::winbase::CREATE_NO_WINDOW);
@@ -371,6 +487,40 @@ pub struct ResolvedRoot {
 }
 
 impl ResolvedRoot {
+    /// Construct a `ResolvedRoot` from the response to a `watch-project`
+    /// request.  This is useful when issuing the request yourself, for
+    /// example over a connection not managed by this crate; otherwise
+    /// `Client::resolve_root` takes care of this for you.
+    pub fn from_response(response: WatchProjectResponse) -> Self {
+        Self {
+            root: response.watch,
+            relative: response.relative_path,
+            watcher: response.watcher,
+        }
+    }
+
+    /// Returns the root path to use in request PDUs such as `QueryRequest`.
+    /// This is the project root rather than the path that was resolved;
+    /// use `apply` to scope a query to the resolved path.
+    pub fn to_query_root(&self) -> PathBuf {
+        self.root.clone()
+    }
+
+    /// Builds a `clock` request for the watch root that synchronizes with
+    /// the filesystem, waiting up to `timeout` for the sync cookie.
+    /// Issuing it right after resolving the root yields a clock that
+    /// reflects all of the changes made before it was issued.
+    pub fn synced_clock(&self, timeout: std::time::Duration) -> ClockRequest {
+        ClockRequest::new(self.to_query_root(), ClockRequestParams::synced(timeout))
+    }
+
+    /// Sets the `relative_root` of `query` so that it is scoped to the
+    /// path that was resolved, and so that the file names in its results
+    /// are relative to that path.
+    pub fn apply(&self, query: &mut QueryRequestCommon) {
+        query.relative_root = self.relative.clone();
+    }
+
     /// Returns the name of the watcher that the server is using to
     /// monitor the path.  The watcher is generally system dependent,
     /// but some systems offer multipler watchers.
@@ -382,6 +532,12 @@ impl ResolvedRoot {
         self.watcher.as_str()
     }
 
+    /// Returns the watcher that the server is using to monitor the path,
+    /// as a `WatcherKind`.  See `watcher` for more details.
+    pub fn watcher_kind(&self) -> WatcherKind {
+        self.watcher.clone().into()
+    }
+
     /// Returns the root of the watchman project that is being watched
     pub fn project_root(&self) -> &Path {
         &self.root
@@ -403,6 +559,62 @@ impl ResolvedRoot {
     }
 }
 
+/// Caches the `ResolvedRoot` for each path that has been resolved, so
+/// that repeatedly resolving the same path doesn't issue a `watch-project`
+/// request each time.
+/// The cache has no means of learning that the server has stopped
+/// watching a root, so `invalidate` must be called if the watch is
+/// removed, for example by `watch-del`, to have the next resolution
+/// re-establish it.
+#[derive(Debug, Default)]
+pub struct RootResolver {
+    roots: HashMap<PathBuf, ResolvedRoot>,
+}
+
+impl RootResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolves `path` using `client`, as with `Client::resolve_root`,
+    /// returning the cached result if it has already been resolved.
+    pub async fn resolve(
+        &mut self,
+        client: &Client,
+        path: CanonicalPath,
+    ) -> Result<ResolvedRoot, Error> {
+        if let Some(root) = self.roots.get(&path.0) {
+            return Ok(root.clone());
+        }
+        let root = client.resolve_root(path.clone()).await?;
+        self.roots.insert(path.0, root.clone());
+        Ok(root)
+    }
+
+    /// Resolves `path` by issuing `watch-project` over `transport`,
+    /// returning the cached result if it has already been resolved.
+    pub fn resolve_over(
+        &mut self,
+        transport: &mut impl Transport,
+        path: CanonicalPath,
+    ) -> Result<ResolvedRoot, Error> {
+        if let Some(root) = self.roots.get(&path.0) {
+            return Ok(root.clone());
+        }
+        let response = run(transport, WatchProjectRequest::new(path.0.clone()))?;
+        let root = ResolvedRoot::from_response(response);
+        self.roots.insert(path.0, root.clone());
+        Ok(root)
+    }
+
+    /// Forgets all of the resolutions whose project root is `root`,
+    /// which should be called after the watch on `root` is removed.
+    pub fn invalidate(&mut self, root: &Path) {
+        self.roots
+            .retain(|_, resolved| resolved.project_root() != root);
+    }
+}
+
 trait ReadWriteStream: AsyncRead + AsyncWrite + std::marker::Unpin + Send {}
 
 #[cfg(unix)]
@@ -426,9 +638,73 @@ enum SubscriptionNotification {
     Canceled,
 }
 
+/// Counts the subscription PDUs that the client task has read from the
+/// server but that have not yet been consumed via `Subscription::next`.
+#[derive(Default)]
+struct PduBacklog {
+    pending: AtomicUsize,
+    threshold: std::sync::Mutex<Option<BacklogThreshold>>,
+}
+
+struct BacklogThreshold {
+    limit: usize,
+    callback: Arc<dyn Fn(usize) + Send + Sync>,
+}
+
+impl PduBacklog {
+    /// Records that a PDU was queued for the subscriber, invoking the
+    /// threshold callback if this pushed the backlog up to its limit.
+    /// The backlog only grows by one at a time, so it reaches the limit
+    /// exactly once on its way up; it has to drain below the limit before
+    /// the callback can fire again.
+    fn push(&self) {
+        let pending = self.pending.fetch_add(1, Ordering::SeqCst) + 1;
+        let callback = {
+            let threshold = self.threshold.lock().expect("backlog lock poisoned");
+            match threshold.as_ref() {
+                Some(threshold) if pending == threshold.limit => Arc::clone(&threshold.callback),
+                _ => return,
+            }
+        };
+        // Invoked without holding the lock so that the callback may
+        // replace the threshold
+        callback(pending);
+    }
+
+    /// Replaces the threshold; see `Subscription::set_backlog_threshold`.
+    fn set_threshold(&self, limit: usize, callback: Arc<dyn Fn(usize) + Send + Sync>) {
+        let mut threshold = self.threshold.lock().expect("backlog lock poisoned");
+        *threshold = Some(BacklogThreshold { limit, callback });
+    }
+
+    /// Records that the subscriber consumed a PDU.
+    fn pop(&self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The client task's end of a subscription.
+struct SubscriptionSink {
+    tx: UnboundedSender<SubscriptionNotification>,
+    backlog: Arc<PduBacklog>,
+}
+
+impl SubscriptionSink {
+    fn send(&self, msg: SubscriptionNotification) -> bool {
+        let is_pdu = matches!(msg, SubscriptionNotification::Pdu(_));
+        if self.tx.send(msg).is_err() {
+            return false;
+        }
+        if is_pdu {
+            self.backlog.push();
+        }
+        true
+    }
+}
+
 enum TaskItem {
     QueueRequest(SendRequest),
-    RegisterSubscription(String, UnboundedSender<SubscriptionNotification>),
+    RegisterSubscription(String, SubscriptionSink),
 }
 
 /// Splits BSER mesages out of a stream. Does not attempt to actually decode them.
@@ -491,7 +767,7 @@ struct ClientTask {
     request_rx: Receiver<TaskItem>,
     request_queue: VecDeque<SendRequest>,
     waiting_response: bool,
-    subscriptions: HashMap<String, UnboundedSender<SubscriptionNotification>>,
+    subscriptions: HashMap<String, SubscriptionSink>,
 }
 
 impl Drop for ClientTask {
@@ -525,8 +801,8 @@ impl ClientTask {
                 task = self.request_rx.recv().fuse() => {
                     match task {
                         Some(TaskItem::QueueRequest(request)) => self.queue_request(request).await?,
-                        Some(TaskItem::RegisterSubscription(name, tx)) => {
-                            self.register_subscription(name, tx)
+                        Some(TaskItem::RegisterSubscription(name, sink)) => {
+                            self.register_subscription(name, sink)
                         }
                         None => break,
                     }
@@ -537,12 +813,8 @@ impl ClientTask {
         Ok(())
     }
 
-    fn register_subscription(
-        &mut self,
-        name: String,
-        tx: UnboundedSender<SubscriptionNotification>,
-    ) {
-        self.subscriptions.insert(name, tx);
+    fn register_subscription(&mut self, name: String, sink: SubscriptionSink) {
+        self.subscriptions.insert(name, sink);
     }
 
     /// Generate an error for each queued request.
@@ -587,6 +859,7 @@ impl ClientTask {
         use serde::Deserialize;
         #[derive(Deserialize, Debug)]
         pub struct Unilateral {
+            #[allow(unused)] // TODO unused warning after rustc upgrade
             pub unilateral: bool,
             pub subscription: String,
             #[serde(default)]
@@ -601,7 +874,7 @@ impl ClientTask {
                     SubscriptionNotification::Pdu(pdu)
                 };
 
-                if subscription.send(msg).is_err() || unilateral.canceled {
+                if !subscription.send(msg) || unilateral.canceled {
                     // The `Subscription` was dropped; we don't need to
                     // treat this as terminal for this client session,
                     // so just de-register the handler
@@ -630,7 +903,7 @@ fn bunser<T>(buf: &[u8]) -> Result<T, Error>
 where
     T: serde::de::DeserializeOwned,
 {
-    let response: T = serde_bser::from_slice(&buf).map_err(|source| Error::Deserialize {
+    let response: T = serde_bser::from_slice(buf).map_err(|source| Error::Deserialize {
         source: source.into(),
         data: buf.to_vec(),
     })?;
@@ -657,11 +930,7 @@ impl ClientInner {
     {
         // Step 1: serialize into a bser byte buffer
         let mut request_data = vec![];
-        serde_bser::ser::serialize(&mut request_data, &request).map_err(|source| {
-            Error::Serialize {
-                source: source.into(),
-            }
-        })?;
+        write_request(&mut request_data, &request)?;
 
         // Step 2: ask the client task to send it for us
         let (tx, rx) = tokio::sync::oneshot::channel();
@@ -679,26 +948,148 @@ impl ClientInner {
             .map_err(|_| ConnectionLost::ClientTaskExited)?
             .map_err(ConnectionLost::Error)?;
 
-        // Step 4: sniff for an error response in the deserialized data
-        use serde::Deserialize;
-        #[derive(Deserialize, Debug)]
-        struct MaybeError {
-            #[serde(default)]
-            error: Option<String>,
-        }
+        // Step 4: decode the response
+        decode_response(&pdu_data, &request)
+    }
+}
+
+/// Decodes the response `pdu_data` to `request`, producing an error if
+/// the server reported one rather than the desired `Response`.
+fn decode_response<Request, Response>(pdu_data: &[u8], request: &Request) -> Result<Response, Error>
+where
+    Request: std::fmt::Debug,
+    Response: serde::de::DeserializeOwned,
+{
+    if let Some(message) = server_error(pdu_data)? {
+        return Err(Error::WatchmanServerError {
+            message,
+            command: format!("{:#?}", request),
+        });
+    }
+
+    // Deserialize into the caller-desired format
+    let response: Response = bunser(pdu_data)?;
+    Ok(response)
+}
+
+/// Sniffs for an error response in `pdu_data`, returning its message.
+fn server_error(pdu_data: &[u8]) -> Result<Option<String>, Error> {
+    use serde::Deserialize;
+    #[derive(Deserialize, Debug)]
+    struct MaybeError {
+        #[serde(default)]
+        error: Option<String>,
+    }
+
+    let maybe_err: MaybeError = bunser(pdu_data)?;
+    Ok(maybe_err.error)
+}
+
+/// The point in time by which a response to a command must be received.
+/// This bounds the whole exchange with the server, whereas `sync_timeout`
+/// only bounds how long the server waits for its cookie sync.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RequestDeadline(pub std::time::Instant);
+
+impl RequestDeadline {
+    /// Returns a deadline `timeout` from now.
+    pub fn after(timeout: std::time::Duration) -> Self {
+        Self(std::time::Instant::now() + timeout)
+    }
+
+    /// Returns the time left until the deadline, or `None` if it has passed.
+    pub fn remaining(&self) -> Option<std::time::Duration> {
+        self.0
+            .checked_duration_since(std::time::Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
 
-        // Step 5: deserialize into the caller-desired format
-        let maybe_err: MaybeError = bunser(&pdu_data)?;
-        if let Some(message) = maybe_err.error {
-            return Err(Error::WatchmanServerError {
-                message,
-                command: format!("{:#?}", request),
-            });
+    /// Returns `Error::DeadlineExceeded` if the deadline has passed.
+    pub fn check(&self) -> Result<(), Error> {
+        match self.remaining() {
+            Some(_) => Ok(()),
+            None => Err(Error::DeadlineExceeded),
         }
+    }
+}
 
-        let response: Response = bunser(&pdu_data)?;
-        Ok(response)
+/// A means of exchanging PDUs with the watchman server, other than the
+/// connection managed by `Client`, for example to mock the server in tests.
+pub trait Transport {
+    /// Sends the BSER encoded `request` to the server, returning the BSER
+    /// encoded response to it.
+    fn round_trip(&mut self, request: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Performs `round_trip`, failing with `Error::DeadlineExceeded` if no
+    /// response is received before `deadline`.
+    /// `run_with_deadline` has no way to interrupt a blocked transport, so
+    /// implementations must bound the wait for the response themselves,
+    /// for example with a read timeout of `deadline.remaining()`.
+    fn round_trip_until(
+        &mut self,
+        request: &[u8],
+        deadline: RequestDeadline,
+    ) -> Result<Vec<u8>, Error>;
+}
+
+/// Issues `cmd` over `transport` and decodes its response.
+pub fn run<C: WatchmanCommand>(
+    transport: &mut impl Transport,
+    cmd: C,
+) -> Result<C::Response, Error> {
+    let mut request_data = vec![];
+    write_request(&mut request_data, &cmd)?;
+    let pdu_data = transport.round_trip(&request_data)?;
+    decode_response(&pdu_data, &cmd)
+}
+
+/// Issues `cmd` over `transport` as `run` does, failing with
+/// `Error::DeadlineExceeded` if no response is received before `deadline`.
+pub fn run_with_deadline<C: WatchmanCommand>(
+    transport: &mut impl Transport,
+    cmd: C,
+    deadline: RequestDeadline,
+) -> Result<C::Response, Error> {
+    let mut request_data = vec![];
+    write_request(&mut request_data, &cmd)?;
+    let pdu_data = transport.round_trip_until(&request_data, deadline)?;
+    decode_response(&pdu_data, &cmd)
+}
+
+/// Waits for `fut` to complete, failing with `Error::Timeout` if that
+/// takes longer than `timeout`.
+async fn with_timeout<T>(
+    timeout: std::time::Duration,
+    request_id: Option<String>,
+    fut: impl std::future::Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    tokio::time::timeout(timeout, fut)
+        .await
+        .map_err(|_| Error::Timeout {
+            request_id,
+            timeout,
+        })?
+}
+
+/// Build the `query` request for `root`, setting the `relative_root`
+/// and `fields` appropriately.
+/// The depths of any `path` generator elements are validated, as the
+/// server doesn't reject negative depths.
+fn query_request(
+    root: &ResolvedRoot,
+    query: QueryRequestCommon,
+    fields: Vec<&'static str>,
+) -> Result<QueryRequest, Error> {
+    query
+        .validate_path_depths()
+        .map_err(|message| Error::InvalidQuery { message })?;
+    let mut query = QueryRequestCommon { fields, ..query };
+    if query.glob_relative_to_project {
+        query.relative_root = None;
+    } else {
+        root.apply(&mut query);
     }
+    Ok(QueryRequest("query", root.to_query_root(), query))
 }
 
 /// Returned by [Subscription::next](struct.Subscription.html#method.next)
@@ -767,6 +1158,7 @@ where
     inner: Arc<Mutex<ClientInner>>,
     root: ResolvedRoot,
     responses: UnboundedReceiver<SubscriptionNotification>,
+    backlog: Arc<PduBacklog>,
     _phantom: PhantomData<F>,
 }
 
@@ -779,6 +1171,33 @@ where
         &self.name
     }
 
+    /// Returns the number of PDUs that have been received from the server
+    /// for this subscription but not yet consumed via `next`.
+    /// A steadily growing value means that this consumer is not keeping up
+    /// with the rate of change in the watched root.
+    pub fn pending_pdus(&self) -> usize {
+        self.backlog.pending.load(Ordering::SeqCst)
+    }
+
+    /// Arranges for `callback` to be invoked, with the current backlog size,
+    /// each time the number of pending PDUs rises to `limit`.
+    /// The callback fires when a new PDU brings the backlog from `limit - 1`
+    /// to `limit`; it does not fire again while the backlog stays at or
+    /// above `limit`, only after `next` has drained it below `limit` and it
+    /// rises back up.  Setting a `limit` that the backlog already exceeds
+    /// therefore has no effect until the backlog drains.
+    /// The callback runs on the client task, so it must not block; it is
+    /// intended for signalling backpressure, for example by switching to
+    /// a subscription with `drop` or `defer` configured.  It may call
+    /// `set_backlog_threshold` itself.
+    /// Replaces any previously configured threshold.
+    pub fn set_backlog_threshold<C>(&self, limit: usize, callback: C)
+    where
+        C: Fn(usize) + Send + Sync + 'static,
+    {
+        self.backlog.set_threshold(limit, Arc::new(callback));
+    }
+
     /// Yield the next set of subscription data.
     /// An error is generated if the subscription is disconnected
     /// from the server.
@@ -792,6 +1211,7 @@ where
 
         match msg {
             SubscriptionNotification::Pdu(pdu) => {
+                self.backlog.pop();
                 let response: QueryResult<F> = bunser(&pdu)?;
 
                 if let Some(state_name) = response.state_enter {
@@ -851,6 +1271,25 @@ impl Client {
         Ok(response)
     }
 
+    /// Issues `cmd` and waits for its response.
+    pub async fn run<C: WatchmanCommand>(&self, cmd: C) -> Result<C::Response, Error> {
+        self.generic_request(cmd).await
+    }
+
+    /// Issues `cmd` as `run` does, but gives up waiting for its response
+    /// once `deadline` passes, returning `Error::DeadlineExceeded`.
+    /// As with `query_with_timeout`, the server still processes the command
+    /// to completion and its response is discarded when it arrives.
+    pub async fn run_with_deadline<C: WatchmanCommand>(
+        &self,
+        cmd: C,
+        deadline: RequestDeadline,
+    ) -> Result<C::Response, Error> {
+        tokio::time::timeout_at(deadline.0.into(), self.run(cmd))
+            .await
+            .map_err(|_| Error::DeadlineExceeded)?
+    }
+
     pub async fn version(&self) -> Result<GetVersionResponse, Error> {
         self.generic_request(&["version"]).await
     }
@@ -943,11 +1382,7 @@ impl Client {
             .generic_request(WatchProjectRequest("watch-project", path.0.clone()))
             .await?;
 
-        Ok(ResolvedRoot {
-            root: response.watch,
-            relative: response.relative_path,
-            watcher: response.watcher,
-        })
+        Ok(ResolvedRoot::from_response(response))
     }
 
     /// Perform a generic watchman query.
@@ -1010,7 +1445,9 @@ impl Client {
     ///
     /// (See [the fields module](fields/index.html) for a definitive list)
     ///
-    /// The file names are all relative to the `root` parameter.
+    /// The file names are all relative to the `root` parameter, unless the
+    /// query sets `glob_relative_to_project`, in which case they are
+    /// relative to its `project_root`.
     pub async fn query<F>(
         &self,
         root: &ResolvedRoot,
@@ -1019,21 +1456,36 @@ impl Client {
     where
         F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
     {
-        let query = QueryRequest(
-            "query",
-            root.root.clone(),
-            QueryRequestCommon {
-                relative_root: root.relative.clone(),
-                fields: F::field_list(),
-                ..query
-            },
-        );
+        let query = query_request(root, query, F::field_list())?;
 
         let response: QueryResult<F> = self.generic_request(query.clone()).await?;
 
         Ok(response)
     }
 
+    /// Perform a query as `query` does, but give up waiting for the
+    /// response after `timeout`, returning `Error::Timeout`.
+    ///
+    /// The server doesn't provide a way to cancel a query once it has
+    /// been issued, so it continues to process the query to completion;
+    /// the response is discarded when it arrives.  The `request_id` of
+    /// the query is included in the error so that the timed out query
+    /// can be correlated with the server side performance logs, and with
+    /// any source control processes that it spawned, which see the id in
+    /// the `HGREQUESTID` environment variable.
+    pub async fn query_with_timeout<F>(
+        &self,
+        root: &ResolvedRoot,
+        query: QueryRequestCommon,
+        timeout: std::time::Duration,
+    ) -> Result<QueryResult<F>, Error>
+    where
+        F: serde::de::DeserializeOwned + std::fmt::Debug + Clone + QueryFieldList,
+    {
+        let request_id = query.request_id.clone();
+        with_timeout(timeout, request_id, self.query(root, query)).await
+    }
+
     /// Create a Subscription that will yield file changes as they occur in
     /// real time.
     /// The `F` type is a struct defined by the
@@ -1047,6 +1499,14 @@ impl Client {
     /// * A [SubscribeResponse](pdu/struct.SubscribeResponse.html) that contains some data about the
     ///   state of the watch at the time the subscription was
     ///   initiated
+    ///
+    /// The server computes the initial results of the subscription while
+    /// processing the `subscribe` command, and always delivers them as the
+    /// first notification on the subscription, immediately following the
+    /// `SubscribeResponse`; there is no option to have them returned in the
+    /// response itself.  The first call to `Subscription::next` will yield
+    /// those initial results, which will be a fresh instance unless the
+    /// `since` field was set in the request.
     pub async fn subscribe<F>(
         &self,
         root: &ResolvedRoot,
@@ -1075,12 +1535,17 @@ impl Client {
         );
 
         let (tx, responses) = tokio::sync::mpsc::unbounded_channel();
+        let backlog = Arc::new(PduBacklog::default());
+        let sink = SubscriptionSink {
+            tx,
+            backlog: Arc::clone(&backlog),
+        };
 
         {
             let inner = self.inner.lock().await;
             inner
                 .request_tx
-                .send(TaskItem::RegisterSubscription(name.clone(), tx))
+                .send(TaskItem::RegisterSubscription(name.clone(), sink))
                 .await
                 .map_err(|_| ConnectionLost::ClientTaskExited)?;
         }
@@ -1090,6 +1555,7 @@ impl Client {
             inner: Arc::clone(&self.inner),
             root: root.clone(),
             responses,
+            backlog,
             _phantom: PhantomData,
         };
 
@@ -1113,8 +1579,7 @@ impl Client {
             )
             .await?;
         Ok(response
-            .files
-            .unwrap_or_else(Vec::new)
+            .into_files()
             .into_iter()
             .map(|f| f.name.into_inner())
             .collect())
@@ -1147,7 +1612,9 @@ impl Client {
                 ClockRequestParams { sync_timeout },
             ))
             .await?;
-        Ok(response.clock)
+        // The `clock` command doesn't accept source control parameters,
+        // so the server always reports a plain clock here
+        Ok(response.clock.spec().clone())
     }
 
     /// Returns the current configuration for a watched root.
@@ -1241,6 +1708,509 @@ mod tests {
         assert!(r1.is_err());
     }
 
+    #[test]
+    fn test_query_error_sync_timeout() {
+        let err = Error::WatchmanServerError {
+            message: "synchronization failed: syncToNow: timed out waiting for cookie \
+                      file to be observed by watcher within 60000 milliseconds: \
+                      Connection timed out"
+                .into(),
+            command: "query".into(),
+        };
+        assert_eq!(
+            err.query_error(),
+            Some(QueryError::SyncTimeout {
+                waited_ms: Some(60000)
+            })
+        );
+
+        let err = Error::WatchmanResponseError {
+            message: "syncToNow: timed out waiting for pending watcher events".into(),
+        };
+        assert_eq!(
+            err.query_error(),
+            Some(QueryError::SyncTimeout { waited_ms: None })
+        );
+
+        let err = Error::WatchmanServerError {
+            message: "failed to parse query: unknown field name 'content.blake3hex'".into(),
+            command: "query".into(),
+        };
+        assert_eq!(
+            err.query_error(),
+            Some(QueryError::UnknownField {
+                field: "content.blake3hex".into()
+            })
+        );
+
+        let err = Error::WatchmanResponseError {
+            message: "failed to parse query: unsupported expression term 'pcre'".into(),
+        };
+        assert_eq!(
+            err.query_error(),
+            Some(QueryError::UnknownExpressionTerm {
+                term: "pcre".into()
+            })
+        );
+
+        let err = Error::WatchmanServerError {
+            message: "unable to resolve root /foo: directory /foo not found".into(),
+            command: "query".into(),
+        };
+        assert_eq!(err.query_error(), None);
+    }
+
+    #[test]
+    fn test_run_with_mock_transport() {
+        struct MockTransport {
+            requests: Vec<Value>,
+            responses: VecDeque<Value>,
+        }
+
+        impl Transport for MockTransport {
+            fn round_trip(&mut self, request: &[u8]) -> Result<Vec<u8>, Error> {
+                self.requests.push(bunser(request)?);
+                let response = self.responses.pop_front().expect("no more responses");
+                Ok(serde_bser::ser::serialize(Vec::new(), response).unwrap())
+            }
+
+            fn round_trip_until(
+                &mut self,
+                request: &[u8],
+                _deadline: RequestDeadline,
+            ) -> Result<Vec<u8>, Error> {
+                self.round_trip(request)
+            }
+        }
+
+        let mut transport = MockTransport {
+            requests: vec![],
+            responses: vec![
+                Value::from(maplit::hashmap! {
+                    "version".to_string() => Value::from("2023.10.02.00"),
+                    "clock".to_string() => Value::from("c:1:2"),
+                }),
+                Value::from(maplit::hashmap! {
+                    "version".to_string() => Value::from("2023.10.02.00"),
+                    "subscribe".to_string() => Value::from("sub"),
+                    "clock".to_string() => Value::from("c:1:3"),
+                }),
+                Value::from(maplit::hashmap! {
+                    "version".to_string() => Value::from("2023.10.02.00"),
+                    "is_fresh_instance".to_string() => Value::from(true),
+                    "clock".to_string() => Value::from("c:1:4"),
+                    "files".to_string() => Value::Array(vec![Value::from("foo")]),
+                }),
+                Value::from(maplit::hashmap! {
+                    "error".to_string() => Value::from("unable to resolve root /bogus"),
+                }),
+            ]
+            .into(),
+        };
+
+        let clock = run(
+            &mut transport,
+            ClockRequest::new("/root".into(), ClockRequestParams::no_sync()),
+        )
+        .unwrap();
+        assert!(
+            matches!(clock.clock, Clock::Spec(ClockSpec::StringClock(ref clock)) if clock == "c:1:2")
+        );
+
+        let subscribe = run(
+            &mut transport,
+            SubscribeCommand::new("/root".into(), "sub".into(), Default::default()),
+        )
+        .unwrap();
+        assert!(
+            matches!(subscribe.clock, Clock::Spec(ClockSpec::StringClock(ref clock)) if clock == "c:1:3")
+        );
+
+        let query = run(
+            &mut transport,
+            QueryRequest::new("/root".into(), Default::default()),
+        )
+        .unwrap();
+        assert!(query.is_fresh_instance);
+        assert_eq!(query.files, Some(vec![Value::from("foo")]));
+
+        let err = run(
+            &mut transport,
+            QueryRequest::new("/bogus".into(), Default::default()),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::WatchmanServerError { .. }));
+
+        let commands: Vec<&Value> = transport
+            .requests
+            .iter()
+            .map(|request| match request {
+                Value::Array(args) => &args[0],
+                other => panic!("unexpected request {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                &Value::from("clock"),
+                &Value::from("subscribe"),
+                &Value::from("query"),
+                &Value::from("query"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolved_root_synced_clock() {
+        let root = ResolvedRoot::from_response(WatchProjectResponse {
+            version: "2023.10.02.00".into(),
+            relative_path: Some("sub/dir".into()),
+            watch: "/repo".into(),
+            watcher: "inotify".into(),
+        });
+        let request = root.synced_clock(std::time::Duration::from_secs(5));
+        assert_eq!(request.0, "clock");
+        assert_eq!(request.1, PathBuf::from("/repo"));
+        assert!(matches!(
+            request.2.sync_timeout,
+            SyncTimeout::Duration(timeout) if timeout == std::time::Duration::from_secs(5)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_backlog() {
+        let (request_tx, _request_rx) = tokio::sync::mpsc::channel(1);
+        let (tx, responses) = tokio::sync::mpsc::unbounded_channel();
+        let backlog = Arc::new(PduBacklog::default());
+        let sink = SubscriptionSink {
+            tx,
+            backlog: Arc::clone(&backlog),
+        };
+        let mut subscription = Subscription::<NameOnly> {
+            name: "sub".into(),
+            inner: Arc::new(Mutex::new(ClientInner { request_tx })),
+            root: ResolvedRoot {
+                root: "/repo".into(),
+                relative: None,
+                watcher: "inotify".into(),
+            },
+            responses,
+            backlog,
+            _phantom: PhantomData,
+        };
+
+        let fired = Arc::new(std::sync::Mutex::new(vec![]));
+        let fired_in_callback = Arc::clone(&fired);
+        subscription.set_backlog_threshold(3, move |pending| {
+            fired_in_callback.lock().unwrap().push(pending);
+        });
+
+        let mut pdu = vec![];
+        serde_bser::ser::serialize(
+            &mut pdu,
+            maplit::hashmap! {
+                "clock" => "c:0:1",
+                "version" => "2023.01.30.00",
+            },
+        )
+        .unwrap();
+        let pdu = Bytes::from(pdu);
+
+        // The server delivers PDUs faster than they are consumed
+        for _ in 0..5 {
+            assert!(sink.send(SubscriptionNotification::Pdu(pdu.clone())));
+        }
+        assert_eq!(subscription.pending_pdus(), 5);
+        assert_eq!(*fired.lock().unwrap(), vec![3]);
+
+        // Draining the backlog re-arms the threshold
+        for _ in 0..4 {
+            let _ = subscription.next().await;
+        }
+        assert_eq!(subscription.pending_pdus(), 1);
+        for _ in 0..2 {
+            assert!(sink.send(SubscriptionNotification::Pdu(pdu.clone())));
+        }
+        assert_eq!(*fired.lock().unwrap(), vec![3, 3]);
+
+        // Cancellation notices don't count towards the backlog
+        assert!(sink.send(SubscriptionNotification::Canceled));
+        assert_eq!(subscription.pending_pdus(), 3);
+
+        // Raising the limit from within the callback doesn't deadlock, and
+        // the new limit applies to subsequent PDUs
+        let backlog = Arc::downgrade(&subscription.backlog);
+        let fired_in_callback = Arc::clone(&fired);
+        subscription.set_backlog_threshold(4, move |pending| {
+            fired_in_callback.lock().unwrap().push(pending);
+            let fired_in_callback = Arc::clone(&fired_in_callback);
+            if let Some(backlog) = backlog.upgrade() {
+                backlog.set_threshold(
+                    pending + 1,
+                    Arc::new(move |pending| fired_in_callback.lock().unwrap().push(pending)),
+                );
+            }
+        });
+        for _ in 0..2 {
+            assert!(sink.send(SubscriptionNotification::Pdu(pdu.clone())));
+        }
+        assert_eq!(*fired.lock().unwrap(), vec![3, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_run_with_deadline() {
+        /// A transport whose server never responds.
+        struct SilentTransport {
+            responses: std::sync::mpsc::Receiver<Vec<u8>>,
+            requests: usize,
+        }
+
+        impl Transport for SilentTransport {
+            fn round_trip(&mut self, _request: &[u8]) -> Result<Vec<u8>, Error> {
+                self.requests += 1;
+                Ok(self.responses.recv().expect("no response"))
+            }
+
+            fn round_trip_until(
+                &mut self,
+                _request: &[u8],
+                deadline: RequestDeadline,
+            ) -> Result<Vec<u8>, Error> {
+                self.requests += 1;
+                let remaining = deadline.remaining().ok_or(Error::DeadlineExceeded)?;
+                self.responses
+                    .recv_timeout(remaining)
+                    .map_err(|_| Error::DeadlineExceeded)
+            }
+        }
+
+        let (_tx, responses) = std::sync::mpsc::channel();
+        let mut transport = SilentTransport {
+            responses,
+            requests: 0,
+        };
+        let clock = || ClockRequest::new("/root".into(), ClockRequestParams::no_sync());
+
+        let deadline = RequestDeadline::after(std::time::Duration::from_millis(20));
+        let err = run_with_deadline(&mut transport, clock(), deadline).unwrap_err();
+        assert!(matches!(err, Error::DeadlineExceeded), "{:?}", err);
+        assert_eq!(deadline.remaining(), None);
+        assert_eq!(transport.requests, 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_run_with_deadline() {
+        // A client whose task never responds to requests
+        let (request_tx, mut request_rx) = tokio::sync::mpsc::channel(1);
+        let client = Client {
+            inner: Arc::new(Mutex::new(ClientInner { request_tx })),
+        };
+        let server = tokio::spawn(async move {
+            let mut pending = vec![];
+            while let Some(item) = request_rx.recv().await {
+                pending.push(item);
+            }
+        });
+
+        let deadline = RequestDeadline::after(std::time::Duration::from_millis(20));
+        let err = client
+            .run_with_deadline(
+                ClockRequest::new("/root".into(), ClockRequestParams::no_sync()),
+                deadline,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::DeadlineExceeded), "{:?}", err);
+        assert_eq!(deadline.remaining(), None);
+
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_root_resolver() {
+        struct CountingTransport {
+            round_trips: usize,
+        }
+
+        impl Transport for CountingTransport {
+            fn round_trip(&mut self, _request: &[u8]) -> Result<Vec<u8>, Error> {
+                self.round_trips += 1;
+                let response = Value::from(maplit::hashmap! {
+                    "version".to_string() => Value::from("2023.10.02.00"),
+                    "watch".to_string() => Value::from("/repo"),
+                    "relative_path".to_string() => Value::from("src"),
+                    "watcher".to_string() => Value::from("inotify"),
+                });
+                Ok(serde_bser::ser::serialize(Vec::new(), response).unwrap())
+            }
+
+            fn round_trip_until(
+                &mut self,
+                request: &[u8],
+                _deadline: RequestDeadline,
+            ) -> Result<Vec<u8>, Error> {
+                self.round_trip(request)
+            }
+        }
+
+        let mut transport = CountingTransport { round_trips: 0 };
+        let mut resolver = RootResolver::new();
+        let path = || CanonicalPath::with_canonicalized_path("/repo/src".into());
+
+        let first = resolver.resolve_over(&mut transport, path()).unwrap();
+        let second = resolver.resolve_over(&mut transport, path()).unwrap();
+        assert_eq!(transport.round_trips, 1);
+        assert_eq!(first.path(), second.path());
+        assert_eq!(second.project_root(), Path::new("/repo"));
+
+        resolver.invalidate(Path::new("/other"));
+        resolver.resolve_over(&mut transport, path()).unwrap();
+        assert_eq!(transport.round_trips, 1);
+
+        resolver.invalidate(Path::new("/repo"));
+        resolver.resolve_over(&mut transport, path()).unwrap();
+        assert_eq!(transport.round_trips, 2);
+    }
+
+    #[test]
+    fn test_resolved_root_from_response() {
+        let root = ResolvedRoot::from_response(WatchProjectResponse {
+            version: "2023.10.02.00".into(),
+            relative_path: Some("sub".into()),
+            watch: "/project".into(),
+            watcher: "inotify".into(),
+        });
+        assert_eq!(root.to_query_root(), PathBuf::from("/project"));
+        assert_eq!(root.path(), PathBuf::from("/project/sub"));
+        assert_eq!(root.watcher(), "inotify");
+        assert_eq!(root.watcher_kind(), WatcherKind::Inotify);
+        let mut query = QueryRequestCommon::default();
+        root.apply(&mut query);
+        assert_eq!(query.relative_root, Some(PathBuf::from("sub")));
+
+        let root = ResolvedRoot::from_response(WatchProjectResponse {
+            version: "2023.10.02.00".into(),
+            relative_path: None,
+            watch: "/project".into(),
+            watcher: "inotify".into(),
+        });
+        assert_eq!(root.to_query_root(), PathBuf::from("/project"));
+        assert_eq!(root.path(), PathBuf::from("/project"));
+        let mut query = QueryRequestCommon {
+            relative_root: Some("stale".into()),
+            ..Default::default()
+        };
+        root.apply(&mut query);
+        assert_eq!(query.relative_root, None);
+    }
+
+    #[test]
+    fn test_query_request_glob_anchoring() {
+        let root = ResolvedRoot {
+            root: "/project".into(),
+            relative: Some("sub".into()),
+            watcher: "inotify".into(),
+        };
+        let query = QueryRequestCommon {
+            glob: Some(vec!["**/*.rs".to_string()]),
+            ..Default::default()
+        };
+
+        let request = query_request(&root, query.clone(), vec!["name"]).unwrap();
+        assert_eq!(request.2.relative_root, Some(PathBuf::from("sub")));
+
+        let request = query_request(
+            &root,
+            QueryRequestCommon {
+                glob_relative_to_project: true,
+                ..query
+            },
+            vec!["name"],
+        )
+        .unwrap();
+        assert_eq!(request.1, PathBuf::from("/project"));
+        assert_eq!(request.2.relative_root, None);
+        assert_eq!(request.2.glob, Some(vec!["**/*.rs".to_string()]));
+
+        // Without a relative_root the server reports names relative to the
+        // project root, so they must be joined onto that rather than onto
+        // the resolved path
+        let mut result: QueryResult<NameOnly> = bunser(
+            &serde_bser::ser::serialize(
+                Vec::new(),
+                maplit::hashmap! {
+                    "version".to_string() => Value::from("2023.10.02.00"),
+                    "clock".to_string() => Value::from("c:1:2"),
+                    "files".to_string() => Value::Array(vec![
+                        Value::from("sub/lib.rs"),
+                        Value::from("other/main.rs"),
+                    ]),
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        result.absolutize(root.project_root(), |f| &mut f.name);
+        let names: Vec<PathBuf> = result
+            .into_files()
+            .into_iter()
+            .map(|f| f.name.into_inner())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                PathBuf::from("/project/sub/lib.rs"),
+                PathBuf::from("/project/other/main.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query_request_negative_depth() {
+        let root = ResolvedRoot {
+            root: "/project".into(),
+            relative: None,
+            watcher: "inotify".into(),
+        };
+        let query = QueryRequestCommon {
+            path: Some(vec![PathGeneratorElement::ConstrainedDepth {
+                path: "src".into(),
+                depth: -1,
+            }]),
+            ..Default::default()
+        };
+        match query_request(&root, query, vec!["name"]) {
+            Err(Error::InvalidQuery { message }) => assert!(message.contains("src"), "{}", message),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_timeout() {
+        let timeout = std::time::Duration::from_secs(5);
+        let err = with_timeout(
+            timeout,
+            Some("req-1".to_string()),
+            futures::future::pending::<Result<(), Error>>(),
+        )
+        .await
+        .unwrap_err();
+        match err {
+            Error::Timeout {
+                request_id,
+                timeout: waited,
+            } => {
+                assert_eq!(request_id, Some("req-1".to_string()));
+                assert_eq!(waited, timeout);
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let value = with_timeout(timeout, None, async { Ok(42) }).await.unwrap();
+        assert_eq!(value, 42);
+    }
+
     #[test]
     fn test_bounds() {
         fn assert_bounds<T: std::error::Error + Sync + Send + 'static>() {}
@@ -8,6 +8,8 @@
 //! This module defines the request and response PDU types used by the
 //! watchman protocol.
 
+use std::collections::HashMap;
+use std::path::Path;
 use std::path::PathBuf;
 
 use serde::Deserialize;
@@ -16,9 +18,204 @@ use serde_bser::value::Value;
 
 use crate::expr::Expr;
 
+/// Defines an enum whose variants map to and from fixed strings, with an
+/// additional `Other(String)` variant that holds any string that is not
+/// recognized.  This allows the enum to tolerate values produced by newer
+/// versions of the server, and to round-trip them unchanged.
+/// Implements `From<String>`, `Into<String>`, `Display` and `as_str`.
+macro_rules! string_enum_with_fallback {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $(
+                $(#[$variant_meta:meta])*
+                $variant:ident => $string:literal,
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $(
+                $(#[$variant_meta])*
+                $variant,
+            )*
+            /// A value that is not known to this version of the crate
+            Other(String),
+        }
+
+        impl $name {
+            /// Returns the string representation used by the server
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $(Self::$variant => $string,)*
+                    Self::Other(s) => s,
+                }
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(s: String) -> Self {
+                match s.as_str() {
+                    $($string => Self::$variant,)*
+                    _ => Self::Other(s),
+                }
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> Self {
+                match value {
+                    $name::Other(s) => s,
+                    value => value.as_str().to_string(),
+                }
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+    };
+}
+
+/// Implements `new` and `with_command` constructors for a request PDU
+/// whose first element is the command name and whose second is the root.
+/// `new` uses the standard command name, while `with_command` allows
+/// it to be overridden, which is useful when talking to a variant of the
+/// server that exposes the command under a different name.
+macro_rules! request_constructors {
+    ($name:ident, $command:literal $(, $arg:ident: $ty:ty)*) => {
+        impl $name {
+            #[doc = concat!("Construct a `", $command, "` request for `root`.")]
+            pub fn new(root: PathBuf $(, $arg: $ty)*) -> Self {
+                Self::with_command($command, root $(, $arg)*)
+            }
+
+            /// Construct the request using `command` in place of the
+            /// standard command name.
+            pub fn with_command(command: &'static str, root: PathBuf $(, $arg: $ty)*) -> Self {
+                Self(command, root $(, $arg)*)
+            }
+        }
+    };
+}
+
+/// The `version` command response.
+/// Fields returned by the server that are not modelled here,
+/// such as `capabilities`, are ignored.
 #[derive(Deserialize, Debug)]
 pub struct GetVersionResponse {
     pub version: String,
+    /// Describes the build of the server, if it was compiled with
+    /// build information embedded.  This is useful for correlating
+    /// client behavior with specific server builds.
+    #[serde(default)]
+    pub buildinfo: Option<Value>,
+}
+
+impl GetVersionResponse {
+    /// Returns the parsed server version, or `None` if the server
+    /// reported a version that doesn't use the standard scheme.
+    pub fn server_version(&self) -> Option<ServerVersion> {
+        ServerVersion::parse(&self.version)
+    }
+}
+
+/// Parses a watchman version string such as `2023.10.02.00` into its
+/// `(year, month, day, patch)` components.
+/// Returns `None` if the string doesn't consist of exactly four numeric
+/// components, which is the case for development builds and for the
+/// older semver style versions such as `4.9.0`.
+pub fn parse_version(version: &str) -> Option<(u32, u32, u32, u32)> {
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+    match (
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next()?,
+        parts.next(),
+    ) {
+        (Some(year), Some(month), Some(day), Some(patch), None) => Some((year, month, day, patch)),
+        _ => None,
+    }
+}
+
+/// A parsed watchman server version, which can be compared to determine
+/// whether the server is newer than some known release.
+/// Watchman uses a date based version scheme, so versions are ordered
+/// by their components in turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ServerVersion {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub patch: u32,
+}
+
+impl ServerVersion {
+    /// Parses a version string; see `parse_version`.
+    pub fn parse(version: &str) -> Option<Self> {
+        let (year, month, day, patch) = parse_version(version)?;
+        Some(Self {
+            year,
+            month,
+            day,
+            patch,
+        })
+    }
+
+    /// Returns true if a server at this version understands `feature`,
+    /// which allows clients to avoid sending fields that an older server
+    /// would reject.
+    pub fn supports(&self, feature: Feature) -> bool {
+        *self >= feature.introduced_in()
+    }
+}
+
+/// Features of the watchman protocol that are only available in
+/// servers from a certain version onwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// The `settle_period` and `settle_timeout` query fields.
+    SettlePeriod,
+    /// Source control aware `since` clocks using `scm.mergebase-with`.
+    ScmSince,
+}
+
+impl Feature {
+    /// Returns the first server version that supports this feature.
+    /// Features that predate the date based version scheme are expressed
+    /// using the components of their older `major.minor` version, which
+    /// sort below any date based version.
+    pub fn introduced_in(self) -> ServerVersion {
+        let (year, month, day, patch) = match self {
+            // Added to query parsing (query/parse.cpp) to work around the
+            // FSEvents ordering issues described in docs/cookies.md, and
+            // first shipped in the v2021.11.15.00 release.
+            Self::SettlePeriod => (2021, 11, 15, 0),
+            // "Since 4.9" according to docs/scm-query.md, and listed as an
+            // experimental feature of Watchman 4.9.0 (2017-08-24) in
+            // docs/release-notes.md.
+            Self::ScmSince => (4, 9, 0, 0),
+        };
+        ServerVersion {
+            year,
+            month,
+            day,
+            patch,
+        }
+    }
+}
+
+impl std::fmt::Display for ServerVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{:02}.{:02}.{:02}",
+            self.year, self.month, self.day, self.patch
+        )
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -32,36 +229,311 @@ pub struct GetSockNameResponse {
     pub version: String,
     pub sockname: Option<PathBuf>,
     pub error: Option<String>,
+    /// The process id of the server, which identifies the server
+    /// instance on machines shared by several users.  Only reported by
+    /// servers that support it; the server in this tree doesn't.
+    pub pid: Option<i64>,
+    /// The user that the server is running as.  Only reported by
+    /// servers that support it; the server in this tree doesn't.
+    pub user: Option<String>,
 }
 
 /// The `clock` command response
 #[derive(Deserialize, Debug)]
 pub struct ClockResponse {
     pub version: String,
-    pub clock: ClockSpec,
+    /// The current clock.  Current servers report this as a plain
+    /// `ClockSpec`, but it is decoded as a `Clock` so that a clock
+    /// embedding source control information can also be represented.
+    pub clock: Clock,
 }
 
 /// The `clock` command request.
 #[derive(Serialize, Debug)]
 pub struct ClockRequest(pub &'static str, pub PathBuf, pub ClockRequestParams);
 
+request_constructors!(ClockRequest, "clock", params: ClockRequestParams);
+
+impl WatchmanCommand for ClockRequest {
+    type Response = ClockResponse;
+}
+
 #[derive(Serialize, Debug)]
 pub struct ClockRequestParams {
     #[serde(skip_serializing_if = "SyncTimeout::is_disabled", default)]
     pub sync_timeout: SyncTimeout,
 }
 
+impl ClockRequestParams {
+    /// Read the current clock without waiting for a sync cookie.
+    /// This is cheaper, and is appropriate when the caller has already
+    /// synchronized with the filesystem, but the clock may not reflect
+    /// very recent changes.
+    pub fn no_sync() -> Self {
+        Self {
+            sync_timeout: SyncTimeout::DisableCookie,
+        }
+    }
+
+    /// Synchronize with the filesystem before reading the clock, failing
+    /// if the sync cookie is not observed within `timeout`.
+    /// A zero `timeout` disables synchronization, as with `no_sync`.
+    pub fn synced(timeout: std::time::Duration) -> Self {
+        Self {
+            sync_timeout: timeout.into(),
+        }
+    }
+}
+
+/// Canonicalize `path` so that it is suitable for use as the root
+/// of a request PDU.
+/// An error is returned if the path doesn't exist or is not a directory.
+/// Note that the server will still resolve the canonical path to its
+/// containing project when processing `watch-project`; use
+/// `Client::resolve_root` to obtain the `ResolvedRoot` for it.
+pub fn canonical_root(path: impl AsRef<Path>) -> std::io::Result<PathBuf> {
+    let path = crate::CanonicalPath::canonicalize(path)?.into_path_buf();
+    if !path.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a directory", path.display()),
+        ));
+    }
+    Ok(path)
+}
+
+/// Resolves the `symlink_target` of the symlink `entry_name`, which is
+/// relative to `watch_root` as returned by a query, into an absolute path.
+/// A relative `target` is interpreted relative to the directory holding
+/// the link, while an absolute `target` is returned as-is.
+/// This is a purely lexical operation: the link is not followed on disk,
+/// and `..` components in `target` are preserved rather than normalized,
+/// so the result may not exist or may pass through other symlinks.
+pub fn resolve_symlink(entry_name: &Path, target: &Path, watch_root: &Path) -> PathBuf {
+    let link = watch_root.join(entry_name);
+    match link.parent() {
+        Some(dir) => dir.join(target),
+        None => target.to_path_buf(),
+    }
+}
+
+/// The framing used by a BSER encoded PDU.
+/// <https://facebook.github.io/watchman/docs/bser.html>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BserVersion {
+    /// BSER v1, which has no capabilities in its header.
+    V1,
+    /// BSER v2, along with the capabilities advertised in its header.
+    V2 { capabilities: u32 },
+}
+
+impl BserVersion {
+    /// Determines the framing of the PDU at the start of `buf` from its
+    /// header, returning `None` if `buf` doesn't start with a BSER header.
+    /// Note that only v2 PDUs can be decoded by this crate.
+    pub fn of_pdu(buf: &[u8]) -> Option<Self> {
+        match buf {
+            [0, 1, ..] => Some(Self::V1),
+            [0, 2, a, b, c, d, ..] => Some(Self::V2 {
+                capabilities: u32::from_ne_bytes([*a, *b, *c, *d]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes the response `pdu_data` to `request` as `Client` does, also
+/// returning the BSER framing that the server used to encode it.
+/// This is intended to help diagnose capability negotiation issues when
+/// exchanging PDUs with the server directly, such as via a `Transport`.
+/// Produces `Error::Deserialize` if `pdu_data` doesn't start with a BSER
+/// header.
+pub fn decode_response_with_version<Request, Response>(
+    pdu_data: &[u8],
+    request: &Request,
+) -> Result<(Response, BserVersion), crate::Error>
+where
+    Request: std::fmt::Debug,
+    Response: serde::de::DeserializeOwned,
+{
+    let version = BserVersion::of_pdu(pdu_data).ok_or_else(|| crate::Error::Deserialize {
+        source: anyhow::anyhow!("PDU doesn't start with a BSER header"),
+        data: pdu_data.to_vec(),
+    })?;
+    let response = crate::decode_response(pdu_data, request)?;
+    Ok((response, version))
+}
+
+/// Decodes the response `pdu_data`, also returning how long that took,
+/// so that the latency of a request can be attributed between waiting for
+/// the server and deserializing its response, which can be significant
+/// for a `QueryResult` holding many files.
+/// As the request isn't known here, a response reporting an error
+/// produces `Error::WatchmanResponseError`, which carries the same
+/// message as the `Error::WatchmanServerError` that `Client` would
+/// produce.
+pub fn decode_response_timed<T>(pdu_data: &[u8]) -> (Result<T, crate::Error>, std::time::Duration)
+where
+    T: serde::de::DeserializeOwned,
+{
+    let start = std::time::Instant::now();
+    let result = match crate::server_error(pdu_data) {
+        Ok(Some(message)) => Err(crate::Error::WatchmanResponseError { message }),
+        Ok(None) => crate::bunser(pdu_data),
+        Err(err) => Err(err),
+    };
+    (result, start.elapsed())
+}
+
+/// Serialize `req` as a BSER PDU directly into `w`.
+/// This avoids materializing the entire encoded request in memory,
+/// which is useful when sending very large requests, such as queries
+/// with many thousands of `path` generator entries, over a socket.
+/// The writer is not flushed.
+pub fn write_request<W: std::io::Write, T: Serialize>(
+    w: &mut W,
+    req: &T,
+) -> Result<(), crate::Error> {
+    serde_bser::ser::serialize(w, req).map_err(|source| crate::Error::Serialize {
+        source: source.into(),
+    })?;
+    Ok(())
+}
+
+/// Render `req` as the equivalent `watchman` command line invocation,
+/// such as `watchman -- query /root '{"fields":["name"]}'`.
+/// This is intended to help reproduce client behavior when debugging;
+/// arguments that are not plain words are quoted for a POSIX shell.
+/// Panics if `req` cannot be represented as JSON, which is the case for
+/// paths that are not valid UTF-8.
+#[cfg(feature = "json")]
+pub fn to_cli_command<T: Serialize>(req: &T) -> String {
+    fn shell_quote(arg: &str) -> String {
+        let is_plain = !arg.is_empty()
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./:@%+=,".contains(c));
+        if is_plain {
+            arg.to_string()
+        } else {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        }
+    }
+
+    let value = serde_json::to_value(req).expect("request is not representable as JSON");
+    let args = match value {
+        serde_json::Value::Array(args) => args,
+        value => vec![value],
+    };
+    let mut command = "watchman --".to_string();
+    for arg in args {
+        let arg = match arg {
+            serde_json::Value::String(s) => s,
+            arg => arg.to_string(),
+        };
+        command.push(' ');
+        command.push_str(&shell_quote(&arg));
+    }
+    command
+}
+
+/// Associates a request PDU with the type of the response that the
+/// server sends for it, so that commands can be issued generically,
+/// for example by `watchman_client::run` over a custom `Transport`, or
+/// by `Client::run`.
+pub trait WatchmanCommand: Serialize + std::fmt::Debug {
+    type Response: serde::de::DeserializeOwned;
+}
+
+/// The basename prefix of the cookie files used by the server to
+/// synchronize with the filesystem.
+const COOKIE_PREFIX: &str = ".watchman-cookie-";
+
 /// The `get-config` command request
 #[derive(Serialize, Debug)]
 pub struct GetConfigRequest(pub &'static str, pub PathBuf);
 
+request_constructors!(GetConfigRequest, "get-config");
+
 /// An incomplete, but typed, representation of the Watchman config file,
 /// which usually lives in /etc/watchman.json. Add new fields as they're
-/// needed, and it might be worth someday exposing the serde_bser::Value
-/// directly so callers can parse it however they want.
+/// needed; any fields that are not modelled here are available in
+/// `extra` so that callers can parse them however they want.
 #[derive(Deserialize, Debug)]
 pub struct WatchmanConfig {
     pub ignore_dirs: Option<Vec<PathBuf>>,
+    /// The list of files that identify the root of a project when
+    /// resolving a path via `watch-project`.
+    pub root_files: Option<Vec<PathBuf>>,
+    /// If set, the server will refuse to watch a directory unless it
+    /// contains at least one of these files.
+    pub root_restrict_files: Option<Vec<PathBuf>>,
+    /// The list of version control metadata directories, relative to the
+    /// root.  If not set, the server defaults to `.git`, `.svn` and `.hg`.
+    pub ignore_vcs: Option<Vec<PathBuf>>,
+    /// Holds the config keys that don't have a typed field above
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+impl WatchmanConfig {
+    /// Returns the directory in which the server places the cookie files
+    /// that it uses to synchronize queries with the filesystem for the
+    /// watch of `root`, following the same rules as the server: the first
+    /// of the `ignore_vcs` directories that exists and is not listed in
+    /// `ignore_dirs`, falling back to `root` itself.
+    ///
+    /// The protocol doesn't allow the cookie directory to be specified
+    /// per query: the server must observe the cookie files through its
+    /// watch, so they must be created inside the watched tree.  Placing
+    /// them in the version control directory avoids disturbing tools that
+    /// monitor the working copy; to influence the placement, configure
+    /// `ignore_vcs` in the `.watchmanconfig` file of the root.
+    pub fn cookie_dir(&self, root: &Path) -> PathBuf {
+        let default_vcs = || [".git", ".svn", ".hg"].map(PathBuf::from).to_vec();
+        let ignore_dirs = self.ignore_dirs.as_deref().unwrap_or_default();
+        self.ignore_vcs
+            .clone()
+            .unwrap_or_else(default_vcs)
+            .into_iter()
+            .filter(|dir| !ignore_dirs.contains(dir))
+            .map(|dir| root.join(dir))
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| root.to_path_buf())
+    }
+
+    /// Returns an expression matching the directories listed in
+    /// `ignore_dirs` and their contents, which can be used to apply the
+    /// same exclusions as the server, for example with
+    /// `QueryRequestCommon::exclude`.  Returns `None` if no directories
+    /// are ignored.
+    /// The `ignore_dirs` are relative to the root of the watch, so the
+    /// expression is only correct for queries without a `relative_root`.
+    pub fn ignore_expression(&self) -> Option<Expr> {
+        let ignore_dirs = self.ignore_dirs.as_ref().filter(|dirs| !dirs.is_empty())?;
+        Some(dirs_and_contents(ignore_dirs.clone()))
+    }
+}
+
+/// Returns an expression matching the directories `dirs`, which are
+/// relative to the root of the query, together with their contents.
+fn dirs_and_contents(dirs: Vec<PathBuf>) -> Expr {
+    let mut terms: Vec<Expr> = dirs
+        .iter()
+        .map(|path| {
+            Expr::DirName(crate::expr::DirNameTerm {
+                path: path.clone(),
+                depth: None,
+            })
+        })
+        .collect();
+    // dirname only matches the contents of the directory, so also
+    // match the directory entry itself
+    terms.push(Expr::Name(crate::expr::NameTerm {
+        paths: dirs,
+        wholename: true,
+    }));
+    Expr::Any(terms)
 }
 
 /// The `get-config` command response
@@ -95,12 +567,97 @@ pub struct StateEnterLeaveResponse {
     pub version: String,
 }
 
+/// The `debug-poison` command request.
+/// This places the watch for the specified root into the poisoned state,
+/// causing subsequent operations on it to fail, which is useful for
+/// exercising client error handling in integration tests.
+/// The final element is the reason for the poisoning; note that current
+/// server versions ignore it and always record the reason as `debug-poison`.
+#[derive(Serialize, Debug)]
+pub struct DebugPoisonRequest(pub &'static str, pub PathBuf, pub String);
+
+request_constructors!(DebugPoisonRequest, "debug-poison", reason: String);
+
+/// The `debug-poison` response
+#[derive(Deserialize, Debug)]
+pub struct DebugPoisonResponse {
+    /// The watchman server version
+    pub version: String,
+    /// The poison reason recorded by the server
+    pub poison: Option<String>,
+}
+
+/// The `debug-prof-dump` command request, which asks the server to dump
+/// a heap profile.  This is only available in servers that use jemalloc
+/// with profiling enabled; otherwise the server reports an error.
+#[derive(Debug)]
+pub struct DebugProfDumpRequest(pub &'static str);
+
+/// A newtype struct would serialize as just its command name, but the
+/// server expects the command to be an array.
+impl Serialize for DebugProfDumpRequest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        [self.0].serialize(serializer)
+    }
+}
+
+impl Default for DebugProfDumpRequest {
+    fn default() -> Self {
+        Self("debug-prof-dump")
+    }
+}
+
+/// The `debug-prof-dump` response
+#[derive(Deserialize, Debug)]
+pub struct DebugProfDumpResponse {
+    /// The watchman server version
+    pub version: String,
+}
+
+impl WatchmanCommand for DebugProfDumpRequest {
+    type Response = DebugProfDumpResponse;
+}
+
+/// The `debug-get-asserted-states` command request
+#[derive(Serialize, Debug)]
+pub struct GetAssertedStatesRequest(pub &'static str, pub PathBuf);
+
+request_constructors!(GetAssertedStatesRequest, "debug-get-asserted-states");
+
+/// The `debug-get-asserted-states` response
+#[derive(Deserialize, Debug)]
+pub struct GetAssertedStatesResponse {
+    /// The watchman server version
+    pub version: String,
+    /// The root that the states are asserted for
+    pub root: PathBuf,
+    /// The states that are currently asserted, or that are pending
+    /// being entered or left
+    pub states: Vec<AssertedState>,
+}
+
+/// Describes a state reported by `debug-get-asserted-states`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AssertedState {
+    /// The name of the state
+    pub name: String,
+    /// The disposition of the state; one of `PendingEnter`, `Asserted`,
+    /// `PendingLeave` or `Done`
+    pub state: String,
+}
+
 /// The `watch-project` command request.
 /// You should use `Client::resolve_root` rather than directly
 /// constructing this type.
 #[derive(Serialize, Debug)]
 pub struct WatchProjectRequest(pub &'static str, pub PathBuf);
 
+request_constructors!(WatchProjectRequest, "watch-project");
+
+impl WatchmanCommand for WatchProjectRequest {
+    type Response = WatchProjectResponse;
+}
+
 /// The `watch-project` response
 #[derive(Deserialize, Debug)]
 pub struct WatchProjectResponse {
@@ -122,18 +679,50 @@ pub struct WatchProjectResponse {
 #[serde(untagged)]
 pub enum PathGeneratorElement {
     RecursivePath(PathBuf),
-    ConstrainedDepth { path: PathBuf, depth: i64 },
+    /// Examines `path` and its descendants up to `depth` levels below it.
+    /// The server treats the depth as unsigned, so a negative depth is
+    /// not rejected but is instead interpreted as a very large depth;
+    /// prefer `PathGeneratorElement::constrained`, which can't be misused
+    /// in that way.
+    ConstrainedDepth {
+        path: PathBuf,
+        depth: i64,
+    },
+}
+
+impl PathGeneratorElement {
+    /// Construct an element that examines `path` and its descendants up
+    /// to `depth` levels below it.  A `depth` of 0 examines just the
+    /// immediate children of `path`.
+    pub fn constrained(path: impl Into<PathBuf>, depth: u32) -> Self {
+        Self::ConstrainedDepth {
+            path: path.into(),
+            depth: depth.into(),
+        }
+    }
 }
 
 /// The `query` request
 #[derive(Serialize, Clone, Debug)]
 pub struct QueryRequest(pub &'static str, pub PathBuf, pub QueryRequestCommon);
 
+request_constructors!(QueryRequest, "query", query: QueryRequestCommon);
+
+/// The files in the response are left undecoded, as their shape depends
+/// on the requested `fields`; use `Client::query` to decode them into a
+/// `query_result_type!` struct.
+impl WatchmanCommand for QueryRequest {
+    type Response = QueryResult<Value>;
+}
+
 #[allow(clippy::trivially_copy_pass_by_ref)]
 fn is_false(v: &bool) -> bool {
     !*v
 }
 
+/// A duration that is sent to the server as integer milliseconds, as
+/// used by the `settle_period`, `settle_timeout` and `lock_timeout`
+/// query fields.
 #[derive(Serialize, Clone, Debug)]
 #[serde(into = "i64")]
 pub struct SettleDurationMs(pub std::time::Duration);
@@ -144,16 +733,17 @@ impl From<std::time::Duration> for SettleDurationMs {
     }
 }
 
-impl Into<i64> for SettleDurationMs {
-    fn into(self) -> i64 {
-        self.0.as_millis() as i64
+impl From<SettleDurationMs> for i64 {
+    fn from(duration: SettleDurationMs) -> Self {
+        duration.0.as_millis() as i64
     }
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, Default)]
 #[serde(into = "i64")]
 pub enum SyncTimeout {
     /// Use the default cookie synchronization timeout
+    #[default]
     Default,
     /// Disable the use of a sync cookie.
     /// This can save ~15ms of latency, but may result in
@@ -170,25 +760,13 @@ pub enum SyncTimeout {
     Duration(std::time::Duration),
 }
 
-impl Default for SyncTimeout {
-    fn default() -> Self {
-        Self::Default
-    }
-}
-
 impl SyncTimeout {
     fn is_default(&self) -> bool {
-        match self {
-            Self::Default => true,
-            _ => false,
-        }
+        matches!(self, Self::Default)
     }
 
     fn is_disabled(&self) -> bool {
-        match self {
-            Self::DisableCookie => true,
-            _ => false,
-        }
+        matches!(self, Self::DisableCookie)
     }
 }
 
@@ -203,9 +781,9 @@ impl From<std::time::Duration> for SyncTimeout {
     }
 }
 
-impl Into<i64> for SyncTimeout {
-    fn into(self) -> i64 {
-        match self {
+impl From<SyncTimeout> for i64 {
+    fn from(timeout: SyncTimeout) -> Self {
+        match timeout {
             // This is only really here because the `ClockRequestParams` PDU
             // treats a missing sync_timeout as `DisableCookie`, whereas
             // the `QueryRequestCommon` PDU treats it as `Default`.
@@ -215,13 +793,45 @@ impl Into<i64> for SyncTimeout {
             // default behavior, we use the current default sync timeout here.
             // We're honestly not likely to change this, so this should be fine.
             // The server uses 1 minute; the value here is expressed in milliseconds.
-            Self::Default => 60_000,
-            Self::DisableCookie => 0,
-            Self::Duration(d) => d.as_millis() as i64,
+            SyncTimeout::Default => 60_000,
+            SyncTimeout::DisableCookie => 0,
+            SyncTimeout::Duration(d) => d.as_millis() as i64,
         }
     }
 }
 
+/// Records whether a synchronized `clock` or `query` request has been
+/// issued for a root, which makes it safe for later requests to use
+/// `SyncTimeout::DisableCookie`; see
+/// `QueryRequestCommon::assert_safe_cookieless`.
+/// The tracker doesn't observe requests itself: record each request once
+/// its response has been received, and keep a separate tracker per root.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncState {
+    synced: bool,
+}
+
+impl SyncState {
+    /// Records that a `clock` request with `params` has completed.
+    pub fn record_clock(&mut self, params: &ClockRequestParams) {
+        self.record(&params.sync_timeout);
+    }
+
+    /// Records that `query` has completed.
+    pub fn record_query(&mut self, query: &QueryRequestCommon) {
+        self.record(&query.sync_timeout);
+    }
+
+    fn record(&mut self, sync_timeout: &SyncTimeout) {
+        self.synced |= !sync_timeout.is_disabled();
+    }
+
+    /// Returns true if a synchronized request has been recorded.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+}
+
 /// The query parameters.
 /// There are a large number of fields that influence the behavior.
 ///
@@ -259,6 +869,25 @@ pub struct QueryRequestCommon {
     #[serde(default, skip_serializing_if = "is_false")]
     pub glob_includedotfiles: bool,
 
+    /// Globs are anchored at the `relative_root` of the query, if it is set,
+    /// otherwise they are anchored at the root of the project.  The server
+    /// has no option to anchor globs at the project root while also using a
+    /// `relative_root`, so setting this to true causes `Client::query` to
+    /// omit the `relative_root` that it would otherwise set from the
+    /// `ResolvedRoot`.
+    ///
+    /// **This changes more than the globs**: the whole query is scoped to
+    /// the project rather than to the resolved path, so every generator and
+    /// expression term is evaluated relative to the project root, and the
+    /// returned file names are relative to `ResolvedRoot::project_root()`
+    /// rather than to `ResolvedRoot::path()`.  Join the names onto the
+    /// project root, for example when using `QueryResult::absolutize`.
+    ///
+    /// This field is not sent to the server; if you are constructing a
+    /// `QueryRequest` yourself, simply leave `relative_root` unset instead.
+    #[serde(skip)]
+    pub glob_relative_to_project: bool,
+
     /// If set, enables the use of the `path` generator.
     /// <https://facebook.github.io/watchman/docs/file-query.html#path-generator>
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -266,6 +895,9 @@ pub struct QueryRequestCommon {
 
     /// If set, enables the use of the `suffix` generator, and specifies the
     /// list of filename suffixes.
+    /// The server matches suffixes case insensitively, regardless of the
+    /// case sensitivity of the filesystem; see `with_suffixes_cs` if you
+    /// need to distinguish `foo.PHP` from `foo.php`.
     /// In virtualized filesystems this can result in an expensive O(project)
     /// filesystem walk, so it is strongly recommended that you scope this to
     /// a relatively shallow subdirectory.
@@ -381,7 +1013,7 @@ pub struct QueryRequestCommon {
     /// You should not normally need to change this.
     /// <https://facebook.github.io/watchman/docs/cmd/query.html#lock-timeout>
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub lock_timeout: Option<i64>,
+    pub lock_timeout: Option<SettleDurationMs>,
 
     /// If set, records the request_id in internal performance sampling data.
     /// It is also exported through the environment as HGREQUESTID so that
@@ -400,6 +1032,419 @@ pub struct QueryRequestCommon {
     pub always_include_directories: bool,
 }
 
+impl QueryRequestCommon {
+    /// Returns a list of fields that are cheap for the server to produce
+    /// with all watchers, including virtualized filesystems such as EdenFS,
+    /// as they don't require materializing inodes.
+    /// Note that `Client::query` replaces `fields` with the field list of
+    /// its result type, which should have the same set of fields.
+    pub fn cheap_fields() -> Vec<&'static str> {
+        vec!["name", "exists", "type"]
+    }
+
+    /// Returns `cheap_fields` together with `content.sha1hex`, which is
+    /// the preferred way to detect content changes, as it is cheaper than
+    /// querying `size` or `mode` on virtualized filesystems.
+    pub fn content_hash_fields() -> Vec<&'static str> {
+        let mut fields = Self::cheap_fields();
+        fields.push("content.sha1hex");
+        fields
+    }
+
+    /// Construct a query that uses the `path` generator to produce only
+    /// the files and directories that are immediate children of `dir`,
+    /// without recursing into its subdirectories.
+    pub fn immediate_children(dir: PathBuf) -> Self {
+        Self {
+            path: Some(vec![PathGeneratorElement::constrained(dir, 0)]),
+            ..Default::default()
+        }
+    }
+
+    /// Construct a query that produces just the specified `files`, for
+    /// example to re-examine the files that a build system reported as
+    /// changed.  The `path` generator examines the immediate children of
+    /// each distinct parent directory, and a `name` term selects the files
+    /// themselves.  The paths are relative to the root of the query.
+    ///
+    /// The `name` term matches the whole name rather than the basename,
+    /// so that a file in one directory doesn't also select a file with the
+    /// same basename in another of the directories.
+    pub fn query_specific_files(files: impl IntoIterator<Item = PathBuf>) -> Self {
+        let files: Vec<PathBuf> = files.into_iter().collect();
+        let mut dirs: Vec<PathBuf> = vec![];
+        for file in &files {
+            let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+        Self {
+            path: Some(
+                dirs.into_iter()
+                    .map(|path| PathGeneratorElement::constrained(path, 0))
+                    .collect(),
+            ),
+            expression: Some(Expr::Name(crate::expr::NameTerm {
+                paths: files,
+                wholename: true,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// Construct a query that uses the `path` generator to produce the
+    /// files beneath `dir`, excluding the `excluded` directories and their
+    /// contents.  The paths are relative to the root of the query.
+    ///
+    /// The exclusion is performed by the expression evaluated for each
+    /// file, so the server still crawls and tracks changes to the excluded
+    /// directories and, when generating results, walks their contents.
+    /// If the directories should never be observed by any client, adding
+    /// them to `ignore_dirs` in the `.watchmanconfig` file is cheaper, as
+    /// the server then doesn't watch them at all.
+    pub fn subtree_excluding(dir: PathBuf, excluded: impl IntoIterator<Item = PathBuf>) -> Self {
+        let excluded: Vec<PathBuf> = excluded.into_iter().collect();
+        let mut query = Self {
+            path: Some(vec![PathGeneratorElement::RecursivePath(dir)]),
+            ..Default::default()
+        };
+        if !excluded.is_empty() {
+            query.exclude(dirs_and_contents(excluded));
+        }
+        query
+    }
+
+    /// Configure the query to wait for the filesystem to settle before
+    /// producing results.  The server waits for a quiet `period` with no
+    /// filesystem changes, failing the query if that doesn't happen within
+    /// `timeout`.
+    /// The server ignores the settle options unless both are specified,
+    /// so prefer this method over setting just one of the fields.
+    pub fn with_settle(
+        mut self,
+        period: std::time::Duration,
+        timeout: std::time::Duration,
+    ) -> Self {
+        self.settle_period = Some(period.into());
+        self.settle_timeout = Some(timeout.into());
+        self
+    }
+
+    /// Restricts the results of this query to files whose suffix matches
+    /// one of `suffixes`, ignoring case, by adding a `suffix` term to the
+    /// `expression` rather than by enabling the `suffix` generator.
+    /// This composes with the other generators, such as `since` or `path`.
+    /// The server always matches suffixes case insensitively, both in the
+    /// generator and in the expression term.
+    pub fn with_suffixes_ci(mut self, suffixes: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.restrict(Expr::Suffix(suffixes.into_iter().collect()));
+        self
+    }
+
+    /// Restricts the results of this query to files whose suffix matches
+    /// one of `suffixes` exactly, including its case.
+    /// The server has no case sensitive form of the `suffix` generator or
+    /// term, so this adds a `match` term for each suffix to the
+    /// `expression` instead.
+    /// Any glob metacharacters in the suffixes are escaped, so they only
+    /// match themselves.
+    /// The `match` term is only case sensitive when the query is, which
+    /// by default depends on the filesystem.  This method leaves that to
+    /// the caller, because `case_sensitive` applies to every `match`,
+    /// `name` and `pcre` term in the query: set it to true as well to
+    /// match case sensitively on case insensitive filesystems.
+    pub fn with_suffixes_cs(mut self, suffixes: impl IntoIterator<Item = PathBuf>) -> Self {
+        let terms = suffixes
+            .into_iter()
+            .map(|suffix| {
+                let mut glob = String::from("*.");
+                for c in suffix.display().to_string().chars() {
+                    if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                        glob.push('\\');
+                    }
+                    glob.push(c);
+                }
+                Expr::Match(crate::expr::MatchTerm {
+                    glob,
+                    include_dot_files: true,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        self.restrict(Expr::Any(terms));
+        self
+    }
+
+    /// Combines `expr` with any existing `expression` using `allof`.
+    fn restrict(&mut self, expr: Expr) {
+        self.expression = Some(match self.expression.take() {
+            Some(existing) => Expr::All(vec![expr, existing]),
+            None => expr,
+        });
+    }
+
+    /// Sets the `path` generator to examine `elements`, whose paths are
+    /// relative to the root of the project rather than to the
+    /// `relative_root` of the query.
+    ///
+    /// The server interprets the paths of the `path` generator relative
+    /// to the `relative_root` when one is set, and relative to the root
+    /// of the watch otherwise.  `watch_relative_path` is the path of the
+    /// `relative_root` relative to the project root, as returned by
+    /// `ResolvedRoot::project_relative_path`; each element is rebased by
+    /// removing it as a prefix.  An error is returned if an element is
+    /// not within `watch_relative_path`, as the server can't examine
+    /// paths outside of the `relative_root`.
+    pub fn path_project_relative(
+        &mut self,
+        elements: Vec<PathGeneratorElement>,
+        watch_relative_path: Option<&Path>,
+    ) -> Result<(), String> {
+        let base = match watch_relative_path {
+            Some(base) => base,
+            None => {
+                self.path = Some(elements);
+                return Ok(());
+            }
+        };
+        let rebase = |path: PathBuf| -> Result<PathBuf, String> {
+            path.strip_prefix(base).map(Path::to_path_buf).map_err(|_| {
+                format!(
+                    "path {} is not within the relative root {}",
+                    path.display(),
+                    base.display()
+                )
+            })
+        };
+        let elements = elements
+            .into_iter()
+            .map(|element| {
+                Ok(match element {
+                    PathGeneratorElement::RecursivePath(path) => {
+                        PathGeneratorElement::RecursivePath(rebase(path)?)
+                    }
+                    PathGeneratorElement::ConstrainedDepth { path, depth } => {
+                        PathGeneratorElement::ConstrainedDepth {
+                            path: rebase(path)?,
+                            depth,
+                        }
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        self.path = Some(elements);
+        Ok(())
+    }
+
+    /// Sets `since` to the clock of `prev`, so that this query produces
+    /// the changes made since `prev` was generated.
+    /// A source control aware clock is passed back as it was returned,
+    /// including the merge base that the server observed, so that the
+    /// server can detect a change in the merge base.
+    pub fn continue_from<F>(&mut self, prev: &QueryResult<F>)
+    where
+        F: std::fmt::Debug + Clone,
+    {
+        self.since = Some(prev.clock.clone());
+    }
+
+    /// Sets `since` to the unix timestamp `seconds` before now, so that this
+    /// query produces only the files changed within that period, which is
+    /// useful to bound the work done after being disconnected for a long
+    /// time rather than processing every change since a saved clock.
+    /// Timestamps have a granularity of 1 second, so files changed in the
+    /// same second as the computed timestamp may or may not be reported;
+    /// see `ClockSpec::unix_timestamp`.
+    pub fn since_age(&mut self, seconds: i64) {
+        self.since_age_at(seconds, std::time::SystemTime::now());
+    }
+
+    fn since_age_at(&mut self, seconds: i64, now: std::time::SystemTime) {
+        let now = match now.duration_since(std::time::UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs() as i64,
+            Err(before_epoch) => -(before_epoch.duration().as_secs() as i64),
+        };
+        self.since = Some(Clock::Spec(ClockSpec::unix_timestamp(
+            now.saturating_sub(seconds),
+        )));
+    }
+
+    /// Excludes files matching `expr` from the results of this query.
+    /// The negation of `expr` is combined with any existing `expression`
+    /// using `allof`, so the existing filter continues to apply.
+    /// This is useful for excluding generated directories such as
+    /// `node_modules` from all of your queries.
+    pub fn exclude(&mut self, expr: Expr) {
+        self.restrict(Expr::Not(Box::new(expr)));
+    }
+
+    /// Checks that the query is well formed before it is sent to the server.
+    /// `Client::query` populates `fields` from the result type, but when the
+    /// query is constructed directly an empty `fields` list is rejected by
+    /// the server with an error that is hard to relate back to its cause.
+    /// The depths of the `path` generator elements must not be negative;
+    /// see `PathGeneratorElement::ConstrainedDepth`.
+    pub fn ensure_valid(&self) -> Result<(), String> {
+        if self.fields.is_empty() {
+            return Err("no fields were requested; include at least `name`".to_string());
+        }
+        self.validate_path_depths()
+    }
+
+    /// Checks that the `name` field is requested.  The server doesn't
+    /// require it, but the results are needed by helpers that operate on
+    /// the names of the files, such as `QueryResult::absolutize` and
+    /// `QueryResult::into_name_map`; call this in addition to `ensure_valid`
+    /// when the results will be used with them.
+    pub fn ensure_requests_name(&self) -> Result<(), String> {
+        if !self.fields.contains(&"name") {
+            return Err(format!(
+                "the `name` field is required but was not requested in {:?}",
+                self.fields
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that the depths of the `path` generator elements are not
+    /// negative; the server would otherwise interpret them as very large
+    /// unsigned depths.  `Client::query` performs this check before the
+    /// query is sent.
+    pub fn validate_path_depths(&self) -> Result<(), String> {
+        for element in self.path.iter().flatten() {
+            if let PathGeneratorElement::ConstrainedDepth { path, depth } = element {
+                if *depth < 0 {
+                    return Err(format!(
+                        "the path generator depth for {} must not be negative, but is {}",
+                        path.display(),
+                        depth
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that disabling the sync cookie for this query is safe.
+    /// A query that sets `sync_timeout` to `SyncTimeout::DisableCookie`
+    /// may observe an outdated view of the filesystem unless a synchronized
+    /// `clock` or query was previously issued for the same root, as
+    /// recorded in `sync_state`.
+    pub fn assert_safe_cookieless(&self, sync_state: &SyncState) -> Result<(), String> {
+        if self.sync_timeout.is_disabled() && !sync_state.is_synced() {
+            return Err(
+                "sync_timeout is DisableCookie, but no synchronized clock or query \
+                 has been issued, so the results may be stale"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks that the configured `relative_root` lies within `watch_root`.
+    /// The server resolves `relative_root` against the root of the watch,
+    /// and silently produces no results if it names a location outside of
+    /// it, which makes this mistake hard to diagnose.
+    /// An absolute `relative_root` must be beneath `watch_root`, and a
+    /// relative one must not use `..` to escape it.  The check is lexical,
+    /// so symlinks are not resolved.
+    /// A query without a `relative_root` is always valid.
+    pub fn validate_relative_root(&self, watch_root: &Path) -> Result<(), String> {
+        let relative_root = match &self.relative_root {
+            Some(relative_root) => relative_root,
+            None => return Ok(()),
+        };
+        let relative = if relative_root.is_absolute() {
+            relative_root.strip_prefix(watch_root).map_err(|_| {
+                format!(
+                    "relative_root {} is not within the watch root {}",
+                    relative_root.display(),
+                    watch_root.display()
+                )
+            })?
+        } else {
+            relative_root.as_path()
+        };
+        let mut depth = 0usize;
+        for component in relative.components() {
+            match component {
+                std::path::Component::ParentDir => {
+                    depth = depth.checked_sub(1).ok_or_else(|| {
+                        format!(
+                            "relative_root {} escapes the watch root {}",
+                            relative_root.display(),
+                            watch_root.display()
+                        )
+                    })?;
+                }
+                std::path::Component::Normal(_) => depth += 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Excludes the cookie files that the server creates in order to
+    /// synchronize queries with the filesystem from the results of this
+    /// query, as with `exclude`.
+    /// The server normally filters these files itself, but in some edge
+    /// cases, such as when they are observed by a different server instance
+    /// sharing the same tree, they can leak into results.
+    /// The cookie files may be placed in the root or in the source control
+    /// metadata directory, so they are matched by basename rather than by
+    /// their location.
+    pub fn exclude_cookies(&mut self) {
+        self.exclude(Expr::Match(crate::expr::MatchTerm {
+            glob: format!("{}*", COOKIE_PREFIX),
+            include_dot_files: true,
+            ..Default::default()
+        }));
+    }
+
+    /// Excludes the `.git`, `.hg` and `.svn` source control metadata
+    /// directories and their contents from the results of this query,
+    /// as with `exclude`.
+    /// The server ignores these directories by default, as configured by
+    /// `ignore_vcs`, but queries using the `path` generator can include
+    /// them explicitly.
+    /// The directories are matched relative to the root of the query, so
+    /// those of nested repositories are not excluded.
+    pub fn exclude_vcs_dirs(&mut self) {
+        self.exclude(dirs_and_contents(
+            [".git", ".hg", ".svn"].iter().map(PathBuf::from).collect(),
+        ));
+    }
+
+    /// Split a query that uses the `path` generator into several queries,
+    /// each of which examines at most `batch_size` of the paths and
+    /// otherwise has the same parameters as this query.
+    /// A query without a `path` generator is returned unchanged.
+    ///
+    /// The results of the batches can be merged by the caller, but note
+    /// that each batch is a separate query: if `since` is set, each batch
+    /// returns its own clock and `is_fresh_instance` status, and files
+    /// may change between batches.  Use the clock returned by the first
+    /// batch as the basis for a subsequent `since` query to avoid
+    /// missing changes.
+    ///
+    /// Panics if `batch_size` is 0.
+    pub fn chunk_paths(mut self, batch_size: usize) -> Vec<QueryRequestCommon> {
+        assert!(batch_size > 0, "batch_size must be greater than 0");
+        let paths = match self.path.take() {
+            Some(paths) => paths,
+            None => return vec![self],
+        };
+        paths
+            .chunks(batch_size)
+            .map(|batch| QueryRequestCommon {
+                path: Some(batch.to_vec()),
+                ..self.clone()
+            })
+            .collect()
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct QueryDebugInfo {
     pub cookie_files: Option<Vec<PathBuf>>,
@@ -425,7 +1470,9 @@ where
     #[serde(default)]
     pub is_fresh_instance: bool,
 
-    /// Holds the list of matching files from the query
+    /// Holds the list of matching files from the query.
+    /// The server may send these using the compact BSER template
+    /// encoding, which is decoded transparently.
     pub files: Option<Vec<F>>,
 
     /// The clock value at the time that these results were generated
@@ -448,24 +1495,492 @@ where
     pub saved_state_info: Option<Value>,
 
     pub debug: Option<QueryDebugInfo>,
+
+    /// The root that produced this result.
+    /// This is set for subscription notifications, which makes it
+    /// possible to tell them apart when one connection has subscriptions
+    /// on multiple roots.  It is not set for the results of a `query`.
+    #[serde(default)]
+    pub root: Option<PathBuf>,
 }
 
-#[derive(Serialize, Default, Clone, Debug)]
-pub struct SubscribeRequest {
-    /// If set, enables the use of the `since` generator and specifies the last
-    /// time you queried the server and for which you wish to receive a delta of
-    /// changes.
-    /// You will typically thread the QueryResult.clock field back to a subsequent
-    /// since query to process the continuity of matching file changes.
-    /// <https://facebook.github.io/watchman/docs/file-query.html#since-generator>
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub since: Option<Clock>,
+impl<F> QueryResult<F>
+where
+    F: std::fmt::Debug + Clone,
+{
+    /// File names in query results are relative to the `relative_root`
+    /// of the query, or to the project root if no `relative_root` was set.
+    /// This method joins each of those names onto `root` to produce
+    /// absolute paths.
+    /// When using `Client::query`, pass `ResolvedRoot::path()` as the `root`.
+    /// The query must request the `name` field; see
+    /// `QueryRequestCommon::ensure_requests_name`.
+    /// Since the layout of `F` is defined by the caller, `name_of` is used
+    /// to access the name field of each file:
+    ///
+    /// ```
+    /// # use watchman_client::prelude::*;
+    /// # fn absolutize(result: &mut QueryResult<NameOnly>, resolved: &ResolvedRoot) {
+    /// result.absolutize(&resolved.path(), |f| &mut f.name);
+    /// # }
+    /// ```
+    pub fn absolutize(&mut self, root: &Path, name_of: impl Fn(&mut F) -> &mut PathBuf) {
+        if let Some(files) = self.files.as_mut() {
+            for file in files {
+                let name = name_of(file);
+                *name = root.join(&*name);
+            }
+        }
+    }
 
-    /// if set, indicates that all input paths are relative to this subdirectory
-    /// in the project, and that all returned filenames will also be relative to
-    /// this subdirectory.
-    /// In large virtualized filesystems it is undesirable to leave this set to
-    /// None as it makes it more likely that you will trigger an O(project)
+    /// Consumes the result, returning the list of matching files.
+    /// Returns an empty list if the server didn't return any files.
+    pub fn into_files(self) -> Vec<F> {
+        self.files.unwrap_or_default()
+    }
+
+    /// Decodes the metadata that accompanied a `state-enter` or
+    /// `state-leave` notification as the caller defined type `M`.
+    /// Returns `None` if the result holds no metadata.
+    pub fn state_metadata_as<M>(&self) -> Option<Result<M, crate::Error>>
+    where
+        M: serde::de::DeserializeOwned,
+    {
+        let metadata = self.state_metadata.as_ref()?;
+        Some(
+            serde_bser::ser::serialize(Vec::new(), metadata)
+                .map_err(|source| crate::Error::Serialize {
+                    source: source.into(),
+                })
+                .and_then(|buf| crate::bunser(&buf)),
+        )
+    }
+
+    /// Removes files that have the same key, as returned by `key_of`, as an
+    /// earlier file in the result, keeping the first occurrence.
+    /// The `dedup_results` query option only removes duplicate names on the
+    /// server side; this can be used to collapse by some other property,
+    /// such as the `ino` field to collapse hard links to the same file.
+    pub fn dedup_by<K: Eq + std::hash::Hash>(&mut self, key_of: impl Fn(&F) -> K) {
+        if let Some(files) = self.files.as_mut() {
+            let mut seen = std::collections::HashSet::new();
+            files.retain(|file| seen.insert(key_of(file)));
+        }
+    }
+
+    /// Consumes the result, returning its files indexed by the name
+    /// returned by `name_of`, which typically accesses the value of the
+    /// `name` field.  If several files have the same name, as can happen
+    /// when `dedup_results` is not set, the last of them is kept.
+    /// The query must request the `name` field; see
+    /// `QueryRequestCommon::ensure_requests_name`.
+    pub fn into_name_map(self, name_of: impl Fn(&F) -> PathBuf) -> HashMap<PathBuf, F> {
+        self.files
+            .unwrap_or_default()
+            .into_iter()
+            .map(|file| (name_of(&file), file))
+            .collect()
+    }
+
+    /// Returns the `ClockSpec` of the result's `clock`, discarding any
+    /// source control information.
+    /// When the query used a named cursor in `since`, the server still
+    /// reports the clock for the point in time at which the results were
+    /// generated rather than the cursor, so the returned value can be
+    /// used to continue from this result without relying on the cursor.
+    pub fn clock_spec(&self) -> &ClockSpec {
+        self.clock.spec()
+    }
+
+    /// Returns true if the caller must discard its state and resynchronize
+    /// from this result: that is the case when an incremental result was
+    /// requested, by passing a clock in `since`, but the server returned a
+    /// fresh instance, for example because it was restarted or because the
+    /// clock has aged out.
+    /// If `empty_on_fresh_instance` was set, the result holds no files and
+    /// the state must be rebuilt by some other means; see `is_empty_fresh`.
+    pub fn needs_full_resync(&self, requested_incremental: bool) -> bool {
+        requested_incremental && self.is_fresh_instance
+    }
+
+    /// Sorts the files in ascending order of modification time, as
+    /// returned by `mtime_of`, which typically accesses the value of the
+    /// `mtime` field.  The server doesn't guarantee any particular order
+    /// for the files in a result.  The sort is stable, so files with
+    /// the same time keep their relative order.
+    pub fn sort_by_mtime(&mut self, mtime_of: impl Fn(&F) -> i64) {
+        if let Some(files) = self.files.as_mut() {
+            files.sort_by_key(mtime_of);
+        }
+    }
+
+    /// Sorts the files in ascending order of inode change time, as
+    /// returned by `ctime_of`, which typically accesses the value of the
+    /// `ctime` field.  See `sort_by_mtime` for more details.
+    pub fn sort_by_ctime(&mut self, ctime_of: impl Fn(&F) -> i64) {
+        if let Some(files) = self.files.as_mut() {
+            files.sort_by_key(ctime_of);
+        }
+    }
+
+    /// Sorts the files in ascending order of name, as returned by
+    /// `name_of`, which typically accesses the value of the `name` field.
+    /// This is intended to normalize the order of results in tests, as
+    /// the server delivers query and subscription results in no
+    /// particular order.
+    pub fn sort_by_name(&mut self, name_of: impl Fn(&F) -> &Path) {
+        if let Some(files) = self.files.as_mut() {
+            files.sort_by(|a, b| name_of(a).cmp(name_of(b)));
+        }
+    }
+
+    /// Returns true if this is a fresh instance result that holds no files.
+    /// This is what the server produces for a fresh instance when the query
+    /// set `empty_on_fresh_instance`; you must then forget any state that
+    /// you were tracking and rebuild it from scratch by some other means.
+    pub fn is_empty_fresh(&self) -> bool {
+        self.is_fresh_instance && self.files.as_ref().is_none_or(Vec::is_empty)
+    }
+
+    /// Interprets the result of a source control aware query, reporting
+    /// whether the changes it holds are relative to a saved state or to the
+    /// `since` clock.
+    /// The server only reports `saved_state_info` when the merge base has
+    /// changed, and only records a `commit-id` in the saved state data of
+    /// the returned clock when it found a suitable saved state.
+    /// <https://facebook.github.io/watchman/docs/scm-query.html>
+    pub fn delta_source(&self) -> DeltaSource {
+        if self.is_fresh_instance {
+            return DeltaSource::FreshInstance;
+        }
+        let saved_state_commit = match &self.clock {
+            Clock::ScmAware(FatClockData {
+                scm:
+                    Some(ScmAwareClockData {
+                        saved_state: Some(saved_state),
+                        ..
+                    }),
+                ..
+            }) => saved_state.commit.as_deref(),
+            _ => None,
+        };
+        match (&self.saved_state_info, saved_state_commit) {
+            (Some(_), Some(commit)) if !commit.is_empty() => DeltaSource::SavedState,
+            _ => DeltaSource::Since,
+        }
+    }
+
+    /// Returns a compact summary of this result, suitable for logging
+    /// or reporting metrics without iterating the files.
+    pub fn summary(&self) -> QuerySummary {
+        QuerySummary {
+            file_count: self.files.as_ref().map_or(0, Vec::len),
+            is_fresh_instance: self.is_fresh_instance,
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+/// Describes how the changes reported by a `QueryResult` were computed,
+/// as returned by `QueryResult::delta_source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaSource {
+    /// The result holds the full set of matching files rather than a
+    /// delta; see `QueryResult::is_fresh_instance`.
+    FreshInstance,
+    /// The merge base changed and the result holds the files that changed
+    /// relative to the saved state identified by the `commit-id` in the
+    /// `saved-state` data of the returned clock.
+    SavedState,
+    /// The result holds the files that changed since the `since` clock.
+    /// This is also the case when the merge base changed but no suitable
+    /// saved state was found.
+    /// Note that when the merge base changes for a source control aware
+    /// query that doesn't specify saved state configuration, the result
+    /// holds the files changed relative to the new merge base; that can't
+    /// be distinguished from the result alone, so compare the `mergebase`
+    /// in the returned clock with the one in the `since` clock to detect it.
+    Since,
+}
+
+/// A compact summary of a `QueryResult`, produced by `QueryResult::summary`.
+/// There is no cancellation status: the server reports the cancellation of
+/// a subscription in a PDU that holds no results, which is surfaced as
+/// `SubscriptionData::Canceled` rather than as a `QueryResult`.
+#[derive(Clone, Debug)]
+pub struct QuerySummary {
+    /// The number of files in the result
+    pub file_count: usize,
+    /// Whether the result was a fresh instance
+    pub is_fresh_instance: bool,
+    /// The clock value at the time that the result was generated
+    pub clock: Clock,
+}
+
+/// A single notification delivered for a subscription, decoded from a
+/// unilateral PDU according to which of its fields are present.
+/// This is useful when handling subscription PDUs directly rather than
+/// via `Subscription::next`, which produces the equivalent
+/// `SubscriptionData`.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum SubscriptionEvent<F>
+where
+    F: std::fmt::Debug + Clone,
+{
+    /// Files matching the subscription have changed.
+    Files(QueryResult<F>),
+    /// Some client has broadcast that the watched project is entering
+    /// the state `name`; see `SubscriptionData::StateEnter`.
+    StateEnter {
+        name: String,
+        metadata: Option<Value>,
+    },
+    /// Some client has broadcast that the watched project is leaving
+    /// the state `name`; see `SubscriptionData::StateLeave`.
+    StateLeave {
+        name: String,
+        metadata: Option<Value>,
+    },
+    /// The subscription was canceled and will deliver no further events.
+    /// The server doesn't report why; when the cancellation is due to
+    /// the watch being removed, the `root` of the watch is included.
+    Canceled { root: Option<PathBuf> },
+}
+
+impl<'de, F> Deserialize<'de> for SubscriptionEvent<F>
+where
+    F: serde::de::DeserializeOwned + std::fmt::Debug + Clone,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // The variants are tried in order, so those identified by the
+        // presence of a particular field must come before `Files`
+        #[allow(clippy::large_enum_variant)]
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        #[serde(bound(deserialize = "F: serde::de::DeserializeOwned"))]
+        enum Event<F>
+        where
+            F: std::fmt::Debug + Clone,
+        {
+            Canceled {
+                #[allow(dead_code)]
+                canceled: bool,
+                root: Option<PathBuf>,
+            },
+            StateEnter {
+                #[serde(rename = "state-enter")]
+                name: String,
+                metadata: Option<Value>,
+            },
+            StateLeave {
+                #[serde(rename = "state-leave")]
+                name: String,
+                metadata: Option<Value>,
+            },
+            Files(QueryResult<F>),
+        }
+
+        Ok(match Event::<F>::deserialize(deserializer)? {
+            Event::Canceled { root, .. } => Self::Canceled { root },
+            Event::StateEnter { name, metadata } => Self::StateEnter { name, metadata },
+            Event::StateLeave { name, metadata } => Self::StateLeave { name, metadata },
+            Event::Files(result) => Self::Files(result),
+        })
+    }
+}
+
+/// A variant of `QueryResult` that can borrow from the buffer holding the
+/// serialized response, rather than allocating owned copies of each field.
+/// When processing very large result sets, defining `F` in terms of
+/// borrowed types such as `&'a str` or `&'a [u8]` avoids an allocation
+/// per field per file:
+///
+/// ```
+/// use serde::Deserialize;
+/// use watchman_client::prelude::*;
+///
+/// #[derive(Deserialize, Debug)]
+/// struct NameAndHash<'a> {
+///     name: &'a str,
+///     #[serde(rename = "content.sha1hex")]
+///     hash: Option<&'a str>,
+/// }
+///
+/// fn decode(pdu: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+///     let result: BorrowedQueryResult<NameAndHash> = serde_bser::from_slice(pdu)?;
+///     Ok(result.files.map(|f| f.len()).unwrap_or(0))
+/// }
+/// ```
+///
+/// Only the subset of the `QueryResult` fields that are relevant to
+/// bulk processing of files are decoded.
+#[derive(Deserialize, Debug)]
+pub struct BorrowedQueryResult<'a, F> {
+    /// The version of the watchman server
+    pub version: &'a str,
+
+    /// See `QueryResult::is_fresh_instance`
+    #[serde(default)]
+    pub is_fresh_instance: bool,
+
+    /// Holds the list of matching files from the query
+    pub files: Option<Vec<F>>,
+
+    /// The clock value at the time that these results were generated
+    pub clock: Clock,
+}
+
+/// The difference between two lists of files, as computed by `diff_results`.
+#[derive(Debug, Clone)]
+pub struct ResultDiff<F> {
+    /// Files that are present only in the newer list
+    pub added: Vec<F>,
+    /// Files that are present only in the older list
+    pub removed: Vec<F>,
+    /// Files that are present in both lists, taken from the newer list.
+    /// Compare their other fields, such as `content.sha1hex` or `oclock`,
+    /// with those of the older list to determine whether they changed.
+    pub present_in_both: Vec<F>,
+}
+
+/// Compares two lists of files, such as the results of two queries made
+/// without the `since` generator, matching entries by their name.
+/// `name_of` is used to access the name field of each file, as the layout
+/// of `F` is defined by the caller.
+/// The files in each part of the result are in the order in which they
+/// appear in the input lists.
+pub fn diff_results<F: Clone>(
+    old: &[F],
+    new: &[F],
+    name_of: impl Fn(&F) -> &Path,
+) -> ResultDiff<F> {
+    let old_names: std::collections::HashSet<&Path> = old.iter().map(&name_of).collect();
+    let new_names: std::collections::HashSet<&Path> = new.iter().map(&name_of).collect();
+
+    let (present_in_both, added) = new
+        .iter()
+        .cloned()
+        .partition(|file| old_names.contains(name_of(file)));
+    let removed = old
+        .iter()
+        .filter(|file| !new_names.contains(name_of(file)))
+        .cloned()
+        .collect();
+
+    ResultDiff {
+        added,
+        removed,
+        present_in_both,
+    }
+}
+
+/// Splits `files` into those without an error and those with one, as
+/// reported by `error_of`, preserving their order.
+/// The server doesn't report a per-file `error` field; the errors that
+/// it reports for individual files are embedded in the value of the field
+/// that failed, which is currently only the case for `content.sha1hex`,
+/// so `error_of` is typically `|f| f.content_sha1hex.error()`.
+pub fn partition_file_errors<F>(
+    files: Vec<F>,
+    error_of: impl Fn(&F) -> Option<&str>,
+) -> (Vec<F>, Vec<F>) {
+    files.into_iter().partition(|file| error_of(file).is_none())
+}
+
+/// Checks that the file result type `F` is compatible with the list of
+/// `fields` requested from the server.
+///
+/// A synthetic file entry holding a plausible value for each of the
+/// requested fields is deserialized into `F`; an error is returned if
+/// that fails, which typically indicates that `F` requires a field that
+/// is not in the list.  Each field is then, in turn, replaced with a
+/// value of the wrong type: if `F` can still be deserialized then it is
+/// reported as a field that was requested but is ignored by `F`.
+///
+/// This is intended to be used in tests to catch mismatches between
+/// field lists and manually defined result structs; note that fields
+/// of `F` that accept any value, such as `Value`, are reported as ignored.
+pub fn validate_fields<F>(fields: &[&str]) -> Result<(), String>
+where
+    F: serde::de::DeserializeOwned,
+{
+    let decode = |wrong_type: Option<&str>| -> Result<F, String> {
+        let value_for = |field: &str| {
+            if Some(field) == wrong_type {
+                Value::Array(vec![Value::Null])
+            } else {
+                sample_field_value(field)
+            }
+        };
+        // The server returns a flat list of names when only the
+        // name field is requested
+        let entry = if fields == ["name"] {
+            value_for("name")
+        } else {
+            Value::Object(
+                fields
+                    .iter()
+                    .map(|&field| (field.to_string(), value_for(field)))
+                    .collect(),
+            )
+        };
+        let binary =
+            serde_bser::ser::serialize(Vec::new(), entry).map_err(|err| err.to_string())?;
+        serde_bser::from_slice(&binary).map_err(|err| err.to_string())
+    };
+
+    decode(None).map_err(|err| format!("unable to deserialize fields {:?}: {}", fields, err))?;
+
+    let ignored: Vec<&str> = fields
+        .iter()
+        .copied()
+        .filter(|field| decode(Some(field)).is_ok())
+        .collect();
+    if ignored.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("fields {:?} are requested but ignored", ignored))
+    }
+}
+
+/// Returns a representative value for the named file result field
+fn sample_field_value(field: &str) -> Value {
+    match field {
+        "name" => Value::ByteString("dir/file.txt".into()),
+        "symlink_target" => "target.txt".into(),
+        "content.sha1hex" => "da39a3ee5e6b4b0d3255bfef95601890afd80709".into(),
+        "cclock" | "oclock" => "c:0:1".into(),
+        "type" => "f".into(),
+        "exists" | "new" => true.into(),
+        "ctime_f" | "mtime_f" => Value::Real(0.0),
+        "ctime" | "ctime_ms" | "ctime_us" | "ctime_ns" | "mtime" | "mtime_ms" | "mtime_us"
+        | "mtime_ns" | "size" | "mode" | "uid" | "gid" | "ino" | "dev" | "nlink" => {
+            Value::Integer(0)
+        }
+        _ => Value::Null,
+    }
+}
+
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct SubscribeRequest {
+    /// If set, enables the use of the `since` generator and specifies the last
+    /// time you queried the server and for which you wish to receive a delta of
+    /// changes.
+    /// You will typically thread the QueryResult.clock field back to a subsequent
+    /// since query to process the continuity of matching file changes.
+    /// <https://facebook.github.io/watchman/docs/file-query.html#since-generator>
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<Clock>,
+
+    /// if set, indicates that all input paths are relative to this subdirectory
+    /// in the project, and that all returned filenames will also be relative to
+    /// this subdirectory.
+    /// In large virtualized filesystems it is undesirable to leave this set to
+    /// None as it makes it more likely that you will trigger an O(project)
     /// filesystem walk.
     /// This field is set automatically from the ResolvedRoot when you perform queries
     /// using Client::query.
@@ -517,13 +2032,69 @@ pub struct SubscribeRequest {
     /// wishes to defer the notification stream.
     /// <https://facebook.github.io/watchman/docs/cmd/subscribe.html#defer>
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
-    pub defer: Vec<&'static str>,
+    pub defer: Vec<String>,
 
     /// The `drop` field specifies a list of state names for which the subscriber
     /// wishes to discard the notification stream.
     /// <https://facebook.github.io/watchman/docs/cmd/subscribe.html#drop>
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
-    pub drop: Vec<&'static str>,
+    pub drop: Vec<String>,
+
+    /// If set, records the request_id in internal performance sampling data
+    /// for the queries run on behalf of this subscription.
+    /// It is also exported through the environment as HGREQUESTID so that
+    /// the context of the subscription can be passed down to any child
+    /// mercurial processes that might be spawned as part of processing
+    /// source control aware queries.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl SubscribeRequest {
+    /// Returns the same list of fields as `QueryRequestCommon::cheap_fields`.
+    pub fn cheap_fields() -> Vec<&'static str> {
+        QueryRequestCommon::cheap_fields()
+    }
+
+    /// Returns the same list of fields as
+    /// `QueryRequestCommon::content_hash_fields`.
+    pub fn content_hash_fields() -> Vec<&'static str> {
+        QueryRequestCommon::content_hash_fields()
+    }
+
+    /// Construct a subscription request that applies the same filter and
+    /// produces the same fields as `query`.
+    /// The `since`, `relative_root`, `expression`, `fields`,
+    /// `case_sensitive`, `empty_on_fresh_instance` and `request_id`
+    /// parameters are copied.
+    /// Subscriptions always use the `since` generator, so the `glob`,
+    /// `path` and `suffix` generators of the query are not transferable;
+    /// express them as terms in the `expression` instead.  The remaining
+    /// query specific parameters, such as the sync and settle options,
+    /// are also not copied.
+    pub fn from_query(query: &QueryRequestCommon) -> Self {
+        Self {
+            since: query.since.clone(),
+            relative_root: query.relative_root.clone(),
+            expression: query.expression.clone(),
+            fields: query.fields.clone(),
+            case_sensitive: query.case_sensitive,
+            empty_on_fresh_instance: query.empty_on_fresh_instance,
+            request_id: query.request_id.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Checks that the file result type `F` is compatible with the
+    /// `fields` of this subscription, as described by `validate_fields`.
+    /// This allows a mismatch to be detected when the subscription is set
+    /// up, rather than when the first notification fails to decode.
+    pub fn validate_fields<F>(&self) -> Result<(), String>
+    where
+        F: serde::de::DeserializeOwned,
+    {
+        validate_fields::<F>(&self.fields)
+    }
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -534,6 +2105,12 @@ pub struct SubscribeCommand(
     pub SubscribeRequest,
 );
 
+request_constructors!(SubscribeCommand, "subscribe", name: String, request: SubscribeRequest);
+
+impl WatchmanCommand for SubscribeCommand {
+    type Response = SubscribeResponse;
+}
+
 /// Returns information about the state of the watch at the time the
 /// subscription was initiated.
 #[derive(Deserialize, Debug)]
@@ -555,13 +2132,44 @@ pub struct SubscribeResponse {
     /// When using source control aware queries with saved
     /// state configuration, this field holds metadata from
     /// the save state storage engine.
+    /// This is copied from the initial results of the subscription,
+    /// which are delivered as the first subscription notification.
     #[serde(rename = "saved-state-info")]
     pub saved_state_info: Option<Value>,
 }
 
+impl SubscribeResponse {
+    /// Returns true if any of the states that were asserted at the time
+    /// that the subscription was initiated are listed in the `defer`
+    /// field of `sub`, the request used to initiate the subscription.
+    /// In that situation, the subscriber should expect that notifications
+    /// will be deferred until those states have been vacated.
+    pub fn is_deferred_for(&self, sub: &SubscribeRequest) -> bool {
+        self.asserted_states
+            .iter()
+            .any(|state| sub.defer.contains(state))
+    }
+
+    /// Attempts to decode `saved_state_info` as `SavedStateClockData`.
+    /// Returns `None` if the field is absent or doesn't have the expected
+    /// structure.
+    /// The `commit` holds the `commit-id` of the saved state that was
+    /// selected by the storage engine; it is `None` if no suitable saved
+    /// state was found, in which case the raw value holds an `error`.
+    /// The remaining fields are specific to the storage engine and can be
+    /// found in the raw value.
+    pub fn saved_state(&self) -> Option<SavedStateClockData> {
+        let info = self.saved_state_info.as_ref()?;
+        let buf = serde_bser::ser::serialize(Vec::new(), info).ok()?;
+        serde_bser::from_slice(&buf).ok()
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct Unsubscribe(pub &'static str, pub PathBuf, pub String);
 
+request_constructors!(Unsubscribe, "unsubscribe", name: String);
+
 #[derive(Deserialize, Debug)]
 pub struct UnsubscribeResponse {
     pub version: String,
@@ -588,6 +2196,81 @@ pub enum Clock {
     ScmAware(FatClockData),
 }
 
+impl Clock {
+    /// Returns the `ClockSpec` of this clock, without any source
+    /// control information.
+    pub fn spec(&self) -> &ClockSpec {
+        match self {
+            Self::Spec(spec) => spec,
+            Self::ScmAware(fat) => &fat.clock,
+        }
+    }
+
+    /// Construct a source control aware clock anchored at the Mercurial
+    /// (or git) `commit`, which is expected to be the merge base of the
+    /// working copy with `mergebase_with` as of some prior point in time.
+    ///
+    /// The server requires `mergebase-with` to be set in order to compute
+    /// the current merge base, and compares it against the `mergebase`
+    /// recorded in the clock, which this method sets to `commit`:
+    ///
+    /// * If they are the same, the query behaves as a normal `since` query
+    ///   against the null clock, yielding a fresh instance.
+    /// * If they differ, the server returns the files that changed between
+    ///   the current merge base and the working copy.
+    ///
+    /// Saved state information is not populated because the server requires
+    /// the `storage` and `config` fields to be specified for that; the
+    /// `commit-id` of a saved state is reported by the server rather than
+    /// being an input.
+    /// <https://facebook.github.io/watchman/docs/scm-query.html>
+    pub fn scm_commit(commit: &str, mergebase_with: &str) -> Self {
+        Self::ScmAware(FatClockData {
+            clock: ClockSpec::null(),
+            scm: Some(ScmAwareClockData {
+                mergebase: Some(commit.to_string()),
+                ..ScmAwareClockData::with_mergebase_with(mergebase_with)
+            }),
+        })
+    }
+
+    /// Construct a source control aware clock anchored at the null clock,
+    /// suitable for starting a fresh source control aware query that
+    /// computes the merge base of the working copy with `mergebase_with`.
+    /// This is equivalent to `FatClockData::scm_query`.
+    /// <https://facebook.github.io/watchman/docs/scm-query.html>
+    pub fn scm_null(mergebase_with: &str) -> Self {
+        FatClockData::scm_query(mergebase_with)
+    }
+
+    /// Construct a source control aware clock that continues from a
+    /// `clock` previously returned by the server, while also asking the
+    /// server to track the merge base of the working copy with
+    /// `mergebase_with`.
+    ///
+    /// `mergebase` must be the merge base that the server observed when it
+    /// produced `clock`, as reported in the `scm` data of that clock.  The
+    /// server compares it with the current merge base: if they are the
+    /// same, the files changed since `clock` are reported, otherwise the
+    /// files changed relative to the current merge base are reported.
+    /// Passing `None` therefore always produces the latter, ignoring
+    /// `clock`; use that only when starting to track a merge base.
+    /// <https://facebook.github.io/watchman/docs/scm-query.html>
+    pub fn since_with_scm(
+        clock: ClockSpec,
+        mergebase_with: &str,
+        mergebase: Option<String>,
+    ) -> Self {
+        Self::ScmAware(FatClockData {
+            clock,
+            scm: Some(ScmAwareClockData {
+                mergebase,
+                ..ScmAwareClockData::with_mergebase_with(mergebase_with)
+            }),
+        })
+    }
+}
+
 /// The fundamental clock specifier string.
 /// The contents of the string should be considered to be opaque to
 /// the client as the server occasionally evolves the meaning of
@@ -596,12 +2279,38 @@ pub enum Clock {
 /// In particular, there is no defined way for a client to reason
 /// about the relationship between any two ClockSpec's.
 ///
+/// `ClockSpec` can also be used directly as the type of the `oclock`
+/// and `cclock` fields in a hand written query result struct; the
+/// server returns those fields as clock strings, which deserialize
+/// as `ClockSpec::StringClock`.  `ObservedClockField` and
+/// `CreatedClockField` are the equivalents for `query_result_type!`.
+///
 /// <https://facebook.github.io/watchman/docs/clockspec.html>
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum ClockSpec {
     StringClock(String),
-    UnixTimestamp(i64),
+    /// Some servers and proxies encode the timestamp as a float;
+    /// any fractional seconds are truncated when deserializing.
+    UnixTimestamp(#[serde(deserialize_with = "deserialize_unix_timestamp")] i64),
+}
+
+/// Deserializes a unix timestamp from either an integer or a float value.
+fn deserialize_unix_timestamp<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        Integer(i64),
+        Float(f64),
+    }
+
+    Ok(match Timestamp::deserialize(deserializer)? {
+        Timestamp::Integer(ts) => ts,
+        Timestamp::Float(ts) => ts as i64,
+    })
 }
 
 /// Construct a null clockspec
@@ -655,13 +2364,62 @@ impl ClockSpec {
     pub fn unix_timestamp(time_t: i64) -> Self {
         Self::UnixTimestamp(time_t)
     }
-}
 
-impl Into<Value> for ClockSpec {
-    fn into(self) -> Value {
+    /// Returns the server pid and tick components of a clock issued by the
+    /// server, or `None` for any other kind of clock.
+    /// Current servers issue clocks of the form
+    /// `c:<start_time>:<pid>:<root_number>:<ticks>`; the `c:<pid>:<ticks>`
+    /// form used by servers up to 2.8.2 is also accepted.
+    /// This is intended for diagnostic purposes only; the clockspec
+    /// format is not a stable API and clients should not otherwise
+    /// reason about its contents.
+    pub fn components(&self) -> Option<(i64, i64)> {
         match self {
-            Self::StringClock(st) => Value::Utf8String(st),
-            Self::UnixTimestamp(ts) => Value::Integer(ts),
+            Self::StringClock(s) => {
+                let parts: Vec<&str> = s.strip_prefix("c:")?.split(':').collect();
+                let (pid, ticks) = match parts.as_slice() {
+                    [_start_time, pid, _root_number, ticks] => (pid, ticks),
+                    [pid, ticks] => (pid, ticks),
+                    _ => return None,
+                };
+                Some((pid.parse().ok()?, ticks.parse().ok()?))
+            }
+            Self::UnixTimestamp(_) => None,
+        }
+    }
+
+    /// Reads a clock from the environment variable `var`, returning `None`
+    /// if it is unset or empty.
+    /// Watchman passes the clock of the triggering query to trigger
+    /// commands in `WATCHMAN_CLOCK`, and the clock that the query was
+    /// issued against in `WATCHMAN_SINCE`, so a tool invoked by a trigger
+    /// can use this to continue from where the trigger left off.
+    pub fn from_env(var: &str) -> Option<Self> {
+        std::env::var(var).ok()?.parse().ok()
+    }
+}
+
+/// Parses a clock in the form produced by the server, or an integer unix
+/// timestamp.
+impl std::str::FromStr for ClockSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("clock must not be empty".to_string());
+        }
+        Ok(match s.parse() {
+            Ok(time_t) => Self::UnixTimestamp(time_t),
+            Err(_) => Self::StringClock(s.to_string()),
+        })
+    }
+}
+
+impl From<ClockSpec> for Value {
+    fn from(clock: ClockSpec) -> Self {
+        match clock {
+            ClockSpec::StringClock(st) => Value::Utf8String(st),
+            ClockSpec::UnixTimestamp(ts) => Value::Integer(ts),
         }
     }
 }
@@ -676,10 +2434,26 @@ pub struct FatClockData {
     pub scm: Option<ScmAwareClockData>,
 }
 
+impl FatClockData {
+    /// Construct a clock suitable for the `since` field of a simple
+    /// source control aware query.
+    /// The clock is anchored at the null clock, so the first query
+    /// will report the files changed relative to the merge base of
+    /// the working copy and `mergebase_with`; subsequent queries should
+    /// use the clock returned in the `QueryResult`.
+    /// <https://facebook.github.io/watchman/docs/scm-query.html>
+    pub fn scm_query(mergebase_with: &str) -> Clock {
+        Clock::ScmAware(Self {
+            clock: ClockSpec::null(),
+            scm: Some(ScmAwareClockData::with_mergebase_with(mergebase_with)),
+        })
+    }
+}
+
 /// Holds extended clock data that includes source control aware
 /// query metadata.
 /// <https://facebook.github.io/watchman/docs/scm-query.html>
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ScmAwareClockData {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mergebase: Option<String>,
@@ -692,6 +2466,18 @@ pub struct ScmAwareClockData {
     pub saved_state: Option<SavedStateClockData>,
 }
 
+impl ScmAwareClockData {
+    /// Construct source control data that computes the merge base
+    /// of the working copy with the named branch, typically
+    /// something like `main` or `master`.
+    pub fn with_mergebase_with(branch: impl Into<String>) -> Self {
+        Self {
+            mergebase_with: Some(branch.into()),
+            ..Default::default()
+        }
+    }
+}
+
 /// Holds extended clock data that includes source control aware
 /// query metadata.
 /// <https://facebook.github.io/watchman/docs/scm-query.html>
@@ -706,6 +2492,48 @@ pub struct SavedStateClockData {
     pub config: Option<Value>,
 }
 
+/// The size of a file in bytes.
+/// Decoding rejects negative values with an error that names the problem,
+/// rather than the generic type mismatch reported for a plain `u64`.
+/// Note that `SizeField` is also unsigned, and so rejects negative sizes.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileSize(pub u64);
+
+impl<'de> Deserialize<'de> for FileSize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Unsigned(u64),
+            Signed(i64),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Unsigned(size) => Ok(Self(size)),
+            Repr::Signed(size) => Err(serde::de::Error::custom(format!(
+                "file size must not be negative, but got {}",
+                size
+            ))),
+        }
+    }
+}
+
+impl std::ops::Deref for FileSize {
+    type Target = u64;
+    fn deref(&self) -> &u64 {
+        &self.0
+    }
+}
+
+impl From<FileSize> for u64 {
+    fn from(size: FileSize) -> Self {
+        size.0
+    }
+}
+
 /// Reports the content SHA1 hash for a file.
 /// Since computing the hash can fail, this struct can also represent
 /// the error that happened during hash computation.
@@ -716,7 +2544,7 @@ pub enum ContentSha1Hex {
     Hash(String),
     /// The error that occured while trying to determine the hash
     Error { error: String },
-    /// Value if the file was deleted.
+    /// Value if the file was deleted, or if it is a directory.
     /// Note that this is distinct from the case where watchman believes
     /// that the file exists and where some other process unlinks it before
     /// watchman can compute the hash: in that racy scenario, the value
@@ -724,74 +2552,124 @@ pub enum ContentSha1Hex {
     None,
 }
 
-/// Encodes the file type field returned in query results and
-/// specified in expression terms.
-///
-/// <https://facebook.github.io/watchman/docs/expr/type.html>
-///
-/// Use this in your query file struct like this:
-///
-/// ```
-/// use serde::Deserialize;
-/// use watchman_client::prelude::*;
-/// #[derive(Deserialize, Debug, Clone)]
-/// struct NameAndType {
-///     name: std::path::PathBuf,
-///     #[serde(rename = "type")]
-///     file_type: FileType,
-/// }
-/// ```
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-#[serde(from = "String", into = "String")]
-pub enum FileType {
-    BlockSpecial,
-    CharSpecial,
-    Directory,
-    Regular,
-    Fifo,
-    Symlink,
-    Socket,
-    SolarisDoor,
-    Unknown,
+impl ContentSha1Hex {
+    /// Returns true if the server computes a content hash for files of
+    /// the given type.
+    /// The server reports `ContentSha1Hex::None` for directories, so when
+    /// a query may match directories, also request the `type` field and
+    /// use this method to distinguish a directory from a file that was
+    /// deleted.  The server follows symlinks when hashing, so a symlink
+    /// reports the hash of its target, or an error if the target cannot
+    /// be read.
+    pub fn is_applicable_for(file_type: &FileType) -> bool {
+        matches!(file_type, FileType::Regular | FileType::Symlink)
+    }
+
+    /// Returns the error that prevented the hash from being computed,
+    /// if any.
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            Self::Error { error } => Some(error),
+            _ => None,
+        }
+    }
 }
 
-impl std::string::ToString for FileType {
-    fn to_string(&self) -> String {
-        (*self).into()
+string_enum_with_fallback! {
+    /// Encodes the file type field returned in query results and
+    /// specified in expression terms.
+    ///
+    /// <https://facebook.github.io/watchman/docs/expr/type.html>
+    ///
+    /// Type codes that are not known to this version of the crate are
+    /// preserved in `FileType::Other`, which is why this is not `Copy`.
+    ///
+    /// Use this in your query file struct like this:
+    ///
+    /// ```
+    /// use serde::Deserialize;
+    /// use watchman_client::prelude::*;
+    /// #[derive(Deserialize, Debug, Clone)]
+    /// struct NameAndType {
+    ///     name: std::path::PathBuf,
+    ///     #[serde(rename = "type")]
+    ///     file_type: FileType,
+    /// }
+    /// ```
+    #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+    #[serde(into = "String")]
+    pub enum FileType {
+        BlockSpecial => "b",
+        CharSpecial => "c",
+        Directory => "d",
+        Regular => "f",
+        Fifo => "p",
+        Symlink => "l",
+        Socket => "s",
+        SolarisDoor => "D",
+        Unknown => "?",
     }
 }
 
-impl From<String> for FileType {
-    fn from(s: String) -> Self {
-        match s.as_ref() {
-            "b" => Self::BlockSpecial,
-            "c" => Self::CharSpecial,
-            "d" => Self::Directory,
-            "f" => Self::Regular,
-            "p" => Self::Fifo,
-            "l" => Self::Symlink,
-            "s" => Self::Socket,
-            "D" => Self::SolarisDoor,
-            "?" => Self::Unknown,
-            unknown => panic!("Watchman Server returned impossible file type {}", unknown),
+impl FileType {
+    /// Decode the file type bits (`S_IFMT`) of a unix `st_mode` value.
+    /// Any permission bits present in `mode` are ignored.
+    pub fn from_mode(mode: u64) -> Self {
+        match mode & 0o170000 {
+            0o060000 => Self::BlockSpecial,
+            0o020000 => Self::CharSpecial,
+            0o040000 => Self::Directory,
+            0o100000 => Self::Regular,
+            0o010000 => Self::Fifo,
+            0o120000 => Self::Symlink,
+            0o140000 => Self::Socket,
+            _ => Self::Unknown,
         }
     }
 }
 
-impl Into<String> for FileType {
-    fn into(self) -> String {
-        match self {
-            Self::BlockSpecial => "b",
-            Self::CharSpecial => "c",
-            Self::Directory => "d",
-            Self::Regular => "f",
-            Self::Fifo => "p",
-            Self::Symlink => "l",
-            Self::Socket => "s",
-            Self::SolarisDoor => "D",
-            Self::Unknown => "?",
+/// Some watchers report the type as a numeric, `st_mode` derived, value
+/// rather than the single character code, so we accept either form.
+impl<'de> Deserialize<'de> for FileType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Code(String),
+            Mode(u64),
         }
-        .to_string()
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Code(code) => code.into(),
+            Repr::Mode(mode) => Self::from_mode(mode),
+        })
+    }
+}
+
+string_enum_with_fallback! {
+    /// The watcher that the server is using to monitor a root, as
+    /// reported by `ResolvedRoot::watcher_kind`.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    #[serde(from = "String", into = "String")]
+    pub enum WatcherKind {
+        /// A virtualized EdenFS checkout
+        Eden => "eden",
+        /// Linux inotify
+        Inotify => "inotify",
+        /// macOS FSEvents
+        FSEvents => "fsevents",
+        /// BSD and macOS kqueue
+        KQueue => "kqueue",
+        /// kqueue for the root directory combined with FSEvents for
+        /// the rest of the tree, on macOS
+        KQueueAndFSEvents => "kqueue+fsevents",
+        /// Windows ReadDirectoryChangesW
+        Win32 => "win32",
+        /// Solaris and illumos event ports
+        PortFs => "portfs",
     }
 }
 
@@ -799,10 +2677,12 @@ impl Into<String> for FileType {
 mod tests {
     use std::collections::HashMap;
 
+    use maplit::hashmap;
     use serde_bser::value::Value;
 
     use super::*;
     use crate::bunser;
+    use crate::fields::*;
 
     fn convert_bser_value<T>(input: Value) -> T
     where
@@ -812,6 +2692,14 @@ mod tests {
         bunser(&binary).unwrap()
     }
 
+    fn serialize_to_value<T>(input: T) -> Value
+    where
+        T: serde::Serialize,
+    {
+        let binary = serde_bser::ser::serialize(Vec::new(), input).unwrap();
+        bunser(&binary).unwrap()
+    }
+
     #[test]
     fn test_content_sha1hex_hash() {
         let value: ContentSha1Hex =
@@ -841,4 +2729,2222 @@ mod tests {
         let value: ContentSha1Hex = convert_bser_value(Value::Null);
         assert_eq!(value, ContentSha1Hex::None);
     }
+
+    #[test]
+    fn test_content_sha1hex_directory() {
+        crate::query_result_type! {
+            struct NameTypeHash {
+                name: NameField,
+                file_type: FileTypeField,
+                hash: ContentSha1HexField,
+            }
+        }
+
+        let file: NameTypeHash = convert_bser_value(
+            hashmap! {
+                "name".to_string() => Value::from("dir"),
+                "type".to_string() => Value::from("d"),
+                "content.sha1hex".to_string() => Value::Null,
+            }
+            .into(),
+        );
+        assert_eq!(*file.name, PathBuf::from("dir"));
+        assert_eq!(*file.hash, ContentSha1Hex::None);
+        assert!(!ContentSha1Hex::is_applicable_for(&file.file_type));
+
+        assert!(ContentSha1Hex::is_applicable_for(&FileType::Regular));
+        assert!(ContentSha1Hex::is_applicable_for(&FileType::Symlink));
+    }
+
+    #[test]
+    fn test_clockspec_unix_timestamp() {
+        let value: ClockSpec = convert_bser_value(Value::Integer(1700000000));
+        assert!(matches!(value, ClockSpec::UnixTimestamp(1700000000)));
+
+        let value: ClockSpec = convert_bser_value(Value::Real(1700000000.75));
+        assert!(matches!(value, ClockSpec::UnixTimestamp(1700000000)));
+
+        let value: ClockSpec = convert_bser_value("c:123:456".into());
+        assert!(matches!(value, ClockSpec::StringClock(s) if s == "c:123:456"));
+    }
+
+    #[test]
+    fn test_subscribe_defer_drop_dynamic_states() {
+        let state = format!("{}.update", "hg");
+        let request = SubscribeRequest {
+            defer: vec![state.clone()],
+            drop: vec![state],
+            ..Default::default()
+        };
+        let value = serialize_to_value(request);
+        let Value::Object(obj) = value else {
+            panic!("expected an object, got {:?}", value);
+        };
+        assert_eq!(obj["defer"], vec![Value::from("hg.update")].into());
+        assert_eq!(obj["drop"], vec![Value::from("hg.update")].into());
+    }
+
+    fn query_result<F>(files: Option<Vec<F>>) -> QueryResult<F>
+    where
+        F: std::fmt::Debug + Clone,
+    {
+        QueryResult {
+            version: "2023.10.02.00".into(),
+            is_fresh_instance: false,
+            files,
+            clock: Clock::Spec(ClockSpec::null()),
+            state_enter: None,
+            state_leave: None,
+            state_metadata: None,
+            saved_state_info: None,
+            debug: None,
+            root: None,
+        }
+    }
+
+    #[test]
+    fn test_absolutize() {
+        let mut result = query_result(Some(vec![
+            NameOnly::from(PathBuf::from("foo.txt")),
+            NameOnly::from(PathBuf::from("bar/baz.txt")),
+        ]));
+        result.absolutize(Path::new("/root/sub"), |f| &mut f.name);
+        let names: Vec<PathBuf> = result
+            .files
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name.into_inner())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                PathBuf::from("/root/sub/foo.txt"),
+                PathBuf::from("/root/sub/bar/baz.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scm_query_clock() {
+        let value = serialize_to_value(FatClockData::scm_query("main"));
+        assert_eq!(
+            value,
+            hashmap! {
+                "clock".to_string() => Value::from("c:0:0"),
+                "scm".to_string() => hashmap! {
+                    "mergebase-with".to_string() => Value::from("main"),
+                }
+                .into(),
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_file_type_code() {
+        let value: FileType = convert_bser_value("f".into());
+        assert!(matches!(value, FileType::Regular));
+
+        let value: FileType = convert_bser_value(Value::ByteString("d".into()));
+        assert!(matches!(value, FileType::Directory));
+    }
+
+    #[test]
+    fn test_file_type_mode() {
+        let value: FileType = convert_bser_value(Value::Integer(0o100644));
+        assert!(matches!(value, FileType::Regular));
+
+        let value: FileType = convert_bser_value(Value::Integer(0o120777));
+        assert!(matches!(value, FileType::Symlink));
+
+        let value: FileType = convert_bser_value(Value::Integer(0o755));
+        assert!(matches!(value, FileType::Unknown));
+    }
+
+    #[test]
+    fn test_string_enum_fallback() {
+        let value: FileType = convert_bser_value("x".into());
+        assert_eq!(value, FileType::Other("x".into()));
+        assert_eq!(serialize_to_value(value), Value::from("x"));
+        assert_eq!(serialize_to_value(FileType::Regular), Value::from("f"));
+
+        let value: WatcherKind = convert_bser_value("inotify".into());
+        assert_eq!(value, WatcherKind::Inotify);
+        let value: WatcherKind = convert_bser_value("fancy-new-watcher".into());
+        assert_eq!(value, WatcherKind::Other("fancy-new-watcher".into()));
+        assert_eq!(value.to_string(), "fancy-new-watcher");
+        assert_eq!(serialize_to_value(value), Value::from("fancy-new-watcher"));
+        assert_eq!(
+            WatcherKind::from("kqueue+fsevents".to_string()),
+            WatcherKind::KQueueAndFSEvents
+        );
+    }
+
+    #[test]
+    fn test_with_settle() {
+        let query = QueryRequestCommon::default().with_settle(
+            std::time::Duration::from_millis(200),
+            std::time::Duration::from_secs(10),
+        );
+        let value = serialize_to_value(query);
+        let Value::Object(obj) = value else {
+            panic!("expected an object, got {:?}", value);
+        };
+        assert_eq!(obj["settle_period"], Value::Integer(200));
+        assert_eq!(obj["settle_timeout"], Value::Integer(10_000));
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let query = QueryRequestCommon {
+            lock_timeout: Some(std::time::Duration::from_secs(2).into()),
+            ..Default::default()
+        };
+        let value = serialize_to_value(query);
+        let Value::Object(obj) = value else {
+            panic!("expected an object, got {:?}", value);
+        };
+        assert_eq!(obj["lock_timeout"], Value::Integer(2_000));
+    }
+
+    #[test]
+    fn test_clockspec_from_env() {
+        let var = "WATCHMAN_CLIENT_TEST_CLOCKSPEC_FROM_ENV";
+        assert!(ClockSpec::from_env(var).is_none());
+
+        std::env::set_var(var, "c:1234:56");
+        assert!(matches!(
+            ClockSpec::from_env(var),
+            Some(ClockSpec::StringClock(clock)) if clock == "c:1234:56"
+        ));
+
+        std::env::set_var(var, "1700000000");
+        assert!(matches!(
+            ClockSpec::from_env(var),
+            Some(ClockSpec::UnixTimestamp(1700000000))
+        ));
+
+        std::env::set_var(var, "");
+        assert!(ClockSpec::from_env(var).is_none());
+        std::env::remove_var(var);
+    }
+
+    #[test]
+    fn test_clockspec_components() {
+        // As issued by a current server
+        assert_eq!(
+            ClockSpec::StringClock("c:1696238011:4242:3:1337".into()).components(),
+            Some((4242, 1337))
+        );
+        // The legacy form
+        assert_eq!(
+            ClockSpec::StringClock("c:1234:56".into()).components(),
+            Some((1234, 56))
+        );
+        assert_eq!(ClockSpec::null().components(), Some((0, 0)));
+        assert_eq!(ClockSpec::StringClock("c:1234".into()).components(), None);
+        assert_eq!(ClockSpec::StringClock("c:1:2:3".into()).components(), None);
+        assert_eq!(
+            ClockSpec::StringClock("c:1:x:3:4".into()).components(),
+            None
+        );
+        assert_eq!(ClockSpec::named_cursor("foo").components(), None);
+        assert_eq!(ClockSpec::unix_timestamp(1234).components(), None);
+    }
+
+    #[test]
+    fn test_config_root_files() {
+        let config: WatchmanConfig = convert_bser_value(
+            hashmap! {
+                "root_files".to_string() => vec![Value::from(".hg"), Value::from(".git")].into(),
+                "root_restrict_files".to_string() => vec![Value::from(".watchmanconfig")].into(),
+            }
+            .into(),
+        );
+        assert_eq!(
+            config.root_files,
+            Some(vec![PathBuf::from(".hg"), PathBuf::from(".git")])
+        );
+        assert_eq!(
+            config.root_restrict_files,
+            Some(vec![PathBuf::from(".watchmanconfig")])
+        );
+
+        let config: WatchmanConfig = convert_bser_value(
+            hashmap! {
+                "ignore_dirs".to_string() => vec![Value::from("buck-out")].into(),
+            }
+            .into(),
+        );
+        assert_eq!(config.ignore_dirs, Some(vec![PathBuf::from("buck-out")]));
+        assert_eq!(config.root_files, None);
+        assert_eq!(config.root_restrict_files, None);
+    }
+
+    #[test]
+    fn test_borrowed_query_result() {
+        #[derive(Deserialize, Debug)]
+        struct BorrowedName<'a> {
+            name: &'a str,
+        }
+
+        let files: Vec<Value> = (0..10_000)
+            .map(|i| {
+                hashmap! {
+                    "name".to_string() => Value::ByteString(format!("dir/file{}.rs", i).into()),
+                }
+                .into()
+            })
+            .collect();
+        let response: Value = hashmap! {
+            "version".to_string() => Value::from("2023.10.02.00"),
+            "clock".to_string() => Value::from("c:0:1"),
+            "files".to_string() => files.into(),
+        }
+        .into();
+        let binary = serde_bser::ser::serialize(Vec::new(), response).unwrap();
+
+        let result: BorrowedQueryResult<BorrowedName> = serde_bser::from_slice(&binary).unwrap();
+        let files = result.files.unwrap();
+        assert_eq!(files.len(), 10_000);
+        assert_eq!(files[42].name, "dir/file42.rs");
+
+        // The names must point into the serialized buffer rather than
+        // into freshly allocated storage
+        let range = binary.as_ptr_range();
+        assert!(files.iter().all(|f| range.contains(&f.name.as_ptr())));
+    }
+
+    #[test]
+    fn test_is_empty_fresh() {
+        let mut result = query_result::<NameOnly>(None);
+        assert!(!result.is_empty_fresh());
+        result.is_fresh_instance = true;
+        assert!(result.is_empty_fresh());
+        result.files = Some(vec![]);
+        assert!(result.is_empty_fresh());
+
+        let mut result = query_result(Some(vec![NameOnly::from(PathBuf::from("foo"))]));
+        result.is_fresh_instance = true;
+        assert!(!result.is_empty_fresh());
+    }
+
+    #[test]
+    fn test_immediate_children() {
+        let query = QueryRequestCommon::immediate_children("foo/bar".into());
+        let value = serialize_to_value(query.path);
+        assert_eq!(
+            value,
+            vec![Value::from(hashmap! {
+                "path".to_string() => Value::from("foo/bar"),
+                "depth".to_string() => Value::Integer(0),
+            })]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_query_specific_files() {
+        let query = QueryRequestCommon::query_specific_files(vec![
+            PathBuf::from("src/lib.rs"),
+            PathBuf::from("src/main.rs"),
+            PathBuf::from("Cargo.toml"),
+            PathBuf::from("src/bin/tool.rs"),
+            PathBuf::from("src/pdu.rs"),
+        ]);
+        let depth0 = |path: &str| {
+            Value::from(hashmap! {
+                "path".to_string() => Value::from(path),
+                "depth".to_string() => Value::Integer(0),
+            })
+        };
+        assert_eq!(
+            serialize_to_value(query.path),
+            vec![depth0("src"), depth0(""), depth0("src/bin")].into()
+        );
+        assert_eq!(
+            Value::from(query.expression.unwrap()),
+            vec![
+                "name".into(),
+                vec![
+                    Value::ByteString("src/lib.rs".into()),
+                    Value::ByteString("src/main.rs".into()),
+                    Value::ByteString("Cargo.toml".into()),
+                    Value::ByteString("src/bin/tool.rs".into()),
+                    Value::ByteString("src/pdu.rs".into()),
+                ]
+                .into(),
+                "wholename".into(),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_clock_request_params() {
+        let value = serialize_to_value(ClockRequestParams::no_sync());
+        assert_eq!(value, Value::Object(HashMap::new()));
+
+        let value = serialize_to_value(ClockRequestParams::synced(
+            std::time::Duration::from_millis(500),
+        ));
+        assert_eq!(
+            value,
+            hashmap! {
+                "sync_timeout".to_string() => Value::Integer(500),
+            }
+            .into()
+        );
+
+        let value = serialize_to_value(ClockRequestParams::synced(std::time::Duration::ZERO));
+        assert_eq!(value, Value::Object(HashMap::new()));
+    }
+
+    #[test]
+    fn test_get_sockname_response() {
+        let response: GetSockNameResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "sockname".to_string() => Value::from("/tmp/watchman.sock"),
+            }
+            .into(),
+        );
+        assert_eq!(response.sockname, Some(PathBuf::from("/tmp/watchman.sock")));
+        assert_eq!(response.pid, None);
+        assert_eq!(response.user, None);
+
+        let response: GetSockNameResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "sockname".to_string() => Value::from("/tmp/watchman.sock"),
+                "pid".to_string() => Value::Integer(1234),
+                "user".to_string() => Value::from("alice"),
+            }
+            .into(),
+        );
+        assert_eq!(response.pid, Some(1234));
+        assert_eq!(response.user.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn test_debug_prof_dump_request() {
+        let value = serialize_to_value(DebugProfDumpRequest::default());
+        assert_eq!(value, vec![Value::from("debug-prof-dump")].into());
+    }
+
+    #[test]
+    fn test_debug_poison_request() {
+        let value = serialize_to_value(DebugPoisonRequest(
+            "debug-poison",
+            "/some/root".into(),
+            "testing".into(),
+        ));
+        assert_eq!(
+            value,
+            vec![
+                Value::from("debug-poison"),
+                Value::from("/some/root"),
+                Value::from("testing"),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_get_asserted_states_response() {
+        let response: GetAssertedStatesResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "root".to_string() => Value::from("/some/root"),
+                "states".to_string() => vec![
+                    Value::from(hashmap! {
+                        "name".to_string() => Value::from("hg.update"),
+                        "state".to_string() => Value::from("Asserted"),
+                    }),
+                ]
+                .into(),
+            }
+            .into(),
+        );
+        assert_eq!(response.root, PathBuf::from("/some/root"));
+        assert_eq!(
+            response.states,
+            vec![AssertedState {
+                name: "hg.update".into(),
+                state: "Asserted".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_is_deferred_for() {
+        let response: SubscribeResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "subscribe".to_string() => Value::from("sub-1"),
+                "clock".to_string() => Value::from("c:0:1"),
+                "asserted-states".to_string() => vec![Value::from("hg.update")].into(),
+            }
+            .into(),
+        );
+
+        let sub = SubscribeRequest {
+            defer: vec!["hg.update".to_string()],
+            ..Default::default()
+        };
+        assert!(response.is_deferred_for(&sub));
+
+        let sub = SubscribeRequest {
+            defer: vec!["hg.transaction".to_string()],
+            drop: vec!["hg.update".to_string()],
+            ..Default::default()
+        };
+        assert!(!response.is_deferred_for(&sub));
+    }
+
+    #[test]
+    fn test_version_buildinfo() {
+        let response: GetVersionResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "buildinfo".to_string() => Value::from("git:deadbeef"),
+                "capabilities".to_string() => hashmap! {
+                    "relative_root".to_string() => Value::Bool(true),
+                }
+                .into(),
+            }
+            .into(),
+        );
+        assert_eq!(response.version, "2023.10.02.00");
+        assert_eq!(response.buildinfo, Some(Value::from("git:deadbeef")));
+
+        let response: GetVersionResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+            }
+            .into(),
+        );
+        assert_eq!(response.buildinfo, None);
+    }
+
+    #[test]
+    fn test_clockspec_field() {
+        #[derive(Deserialize, Debug)]
+        struct ClockedFile {
+            name: PathBuf,
+            oclock: ClockSpec,
+        }
+
+        for oclock in [
+            Value::from("c:1:2:3:4"),
+            Value::ByteString(b"c:1:2:3:4".to_vec().into()),
+        ] {
+            let file: ClockedFile = convert_bser_value(
+                hashmap! {
+                    "name".to_string() => Value::from("foo"),
+                    "oclock".to_string() => oclock,
+                }
+                .into(),
+            );
+            assert_eq!(file.name, PathBuf::from("foo"));
+            match file.oclock {
+                ClockSpec::StringClock(clock) => assert_eq!(clock, "c:1:2:3:4"),
+                other => panic!("unexpected clock {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    #[allow(dead_code)]
+    fn test_validate_fields() {
+        #[derive(Deserialize, Debug)]
+        struct NameAndExists {
+            name: PathBuf,
+            exists: bool,
+        }
+
+        assert_eq!(
+            validate_fields::<NameAndExists>(&["name", "exists"]),
+            Ok(())
+        );
+        assert_eq!(validate_fields::<NameOnly>(&["name"]), Ok(()));
+
+        crate::query_result_type! {
+            struct NameAndHash {
+                name: NameField,
+                hash: ContentSha1HexField,
+            }
+        }
+        assert_eq!(
+            validate_fields::<NameAndHash>(&NameAndHash::field_list()),
+            Ok(())
+        );
+        assert!(validate_fields::<NameAndHash>(&["name", "content.sha1hex", "exists"]).is_err());
+
+        let err = validate_fields::<NameAndExists>(&["name", "exists", "size"]).unwrap_err();
+        assert_eq!(err, "fields [\"size\"] are requested but ignored");
+
+        let err = validate_fields::<NameAndExists>(&["name", "size"]).unwrap_err();
+        assert!(err.contains("exists"), "{}", err);
+    }
+
+    #[test]
+    #[allow(dead_code)]
+    fn test_subscribe_request_validate_fields() {
+        #[derive(Deserialize, Debug)]
+        struct NameAndExists {
+            name: PathBuf,
+            exists: bool,
+        }
+
+        let sub = SubscribeRequest {
+            fields: vec!["name", "exists"],
+            ..Default::default()
+        };
+        assert_eq!(sub.validate_fields::<NameAndExists>(), Ok(()));
+
+        let sub = SubscribeRequest {
+            fields: vec!["name", "size"],
+            ..Default::default()
+        };
+        let err = sub.validate_fields::<NameAndExists>().unwrap_err();
+        assert!(err.contains("exists"), "{}", err);
+    }
+
+    #[test]
+    fn test_scm_commit_clock() {
+        let value = serialize_to_value(Clock::scm_commit("deadbeef", "main"));
+        assert_eq!(
+            value,
+            hashmap! {
+                "clock".to_string() => Value::from("c:0:0"),
+                "scm".to_string() => hashmap! {
+                    "mergebase".to_string() => Value::from("deadbeef"),
+                    "mergebase-with".to_string() => Value::from("main"),
+                }
+                .into(),
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_scm_null_clock() {
+        let value = serialize_to_value(Clock::scm_null("main"));
+        assert_eq!(
+            value,
+            hashmap! {
+                "clock".to_string() => Value::from("c:0:0"),
+                "scm".to_string() => hashmap! {
+                    "mergebase-with".to_string() => Value::from("main"),
+                }
+                .into(),
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_since_with_scm_clock() {
+        // The clock returned by a previous source control aware query
+        let prev: Clock = convert_bser_value(
+            hashmap! {
+                "clock".to_string() => Value::from("c:1:2:3:4"),
+                "scm".to_string() => hashmap! {
+                    "mergebase".to_string() => Value::from("abc123"),
+                    "mergebase-with".to_string() => Value::from("main"),
+                }
+                .into(),
+            }
+            .into(),
+        );
+        let (clock, mergebase) = match prev {
+            Clock::ScmAware(FatClockData {
+                clock,
+                scm: Some(scm),
+            }) => (clock, scm.mergebase),
+            other => panic!("unexpected clock {:?}", other),
+        };
+
+        // Both the clock and the merge base that it was observed at are
+        // sent, so that the server reports the changes since the clock
+        // for as long as the merge base is unchanged
+        assert_eq!(
+            serialize_to_value(Clock::since_with_scm(clock.clone(), "main", mergebase)),
+            hashmap! {
+                "clock".to_string() => Value::from("c:1:2:3:4"),
+                "scm".to_string() => hashmap! {
+                    "mergebase".to_string() => Value::from("abc123"),
+                    "mergebase-with".to_string() => Value::from("main"),
+                }
+                .into(),
+            }
+            .into()
+        );
+
+        // Without a merge base, the server treats it as having changed
+        assert_eq!(
+            serialize_to_value(Clock::since_with_scm(clock, "main", None)),
+            hashmap! {
+                "clock".to_string() => Value::from("c:1:2:3:4"),
+                "scm".to_string() => hashmap! {
+                    "mergebase-with".to_string() => Value::from("main"),
+                }
+                .into(),
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_into_files() {
+        let result = query_result(Some(vec![NameOnly::from(PathBuf::from("foo"))]));
+        let files = result.into_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(*files[0].name, PathBuf::from("foo"));
+
+        let result = query_result::<NameOnly>(None);
+        assert!(result.into_files().is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_response_initial_saved_state() {
+        let info: Value = hashmap! {
+            "manifold-path".to_string() => Value::from("tree/foo"),
+        }
+        .into();
+        let response: SubscribeResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "subscribe".to_string() => Value::from("sub-1"),
+                "clock".to_string() => Value::from("c:0:1"),
+                "asserted-states".to_string() => Value::Array(vec![]),
+                "saved-state-info".to_string() => info.clone(),
+            }
+            .into(),
+        );
+        assert_eq!(response.saved_state_info, Some(info));
+        assert!(response.asserted_states.is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_response_saved_state() {
+        let response = |info: Option<Value>| -> SubscribeResponse {
+            let mut obj = hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "subscribe".to_string() => Value::from("sub-1"),
+                "clock".to_string() => Value::from("c:0:1"),
+            };
+            if let Some(info) = info {
+                obj.insert("saved-state-info".to_string(), info);
+            }
+            convert_bser_value(obj.into())
+        };
+
+        let saved_state = response(Some(
+            hashmap! {
+                "local-path".to_string() => Value::from("/saved/state/abc123"),
+                "commit-id".to_string() => Value::from("abc123"),
+            }
+            .into(),
+        ))
+        .saved_state()
+        .unwrap();
+        assert_eq!(saved_state.commit, Some("abc123".to_string()));
+        assert_eq!(saved_state.storage, None);
+
+        assert!(response(None).saved_state().is_none());
+        assert!(response(Some("bogus".into())).saved_state().is_none());
+    }
+
+    #[test]
+    fn test_subscribe_request_id() {
+        let query = serialize_to_value(QueryRequestCommon {
+            request_id: Some("req-1".into()),
+            ..Default::default()
+        });
+        let sub = serialize_to_value(SubscribeRequest {
+            request_id: Some("req-1".into()),
+            ..Default::default()
+        });
+        let (Value::Object(query), Value::Object(sub)) = (query, sub) else {
+            panic!("expected objects");
+        };
+        assert_eq!(sub["request_id"], Value::from("req-1"));
+        assert_eq!(sub["request_id"], query["request_id"]);
+
+        let Value::Object(sub) = serialize_to_value(SubscribeRequest::default()) else {
+            panic!("expected an object");
+        };
+        assert!(!sub.contains_key("request_id"));
+    }
+
+    #[test]
+    fn test_diff_results() {
+        let files = |names: &[&str]| -> Vec<NameOnly> {
+            names
+                .iter()
+                .map(|name| NameOnly::from(PathBuf::from(name)))
+                .collect()
+        };
+        let names = |files: &[NameOnly]| -> Vec<PathBuf> {
+            files.iter().map(|f| f.name.clone().into_inner()).collect()
+        };
+        fn name_of(f: &NameOnly) -> &Path {
+            &f.name
+        }
+
+        let old = files(&["a", "b", "c"]);
+        let new = files(&["b", "c", "d"]);
+        let diff = diff_results(&old, &new, name_of);
+        assert_eq!(names(&diff.added), vec![PathBuf::from("d")]);
+        assert_eq!(names(&diff.removed), vec![PathBuf::from("a")]);
+        assert_eq!(
+            names(&diff.present_in_both),
+            vec![PathBuf::from("b"), PathBuf::from("c")]
+        );
+
+        let diff = diff_results(&files(&["a"]), &files(&["b"]), name_of);
+        assert_eq!(names(&diff.added), vec![PathBuf::from("b")]);
+        assert_eq!(names(&diff.removed), vec![PathBuf::from("a")]);
+        assert!(diff.present_in_both.is_empty());
+    }
+
+    #[test]
+    fn test_partition_file_errors() {
+        crate::query_result_type! {
+            struct NameAndHash {
+                name: NameField,
+                hash: ContentSha1HexField,
+            }
+        }
+
+        let files: Vec<NameAndHash> = vec![
+            ("a", Value::from("e820c2c600a36f05ba905cf1bf32c4834e804e22")),
+            (
+                "b",
+                hashmap! {
+                    "error".to_string() => Value::from("Permission denied"),
+                }
+                .into(),
+            ),
+            ("c", Value::Null),
+            (
+                "d",
+                hashmap! {
+                    "error".to_string() => Value::from("No such device"),
+                }
+                .into(),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, hash)| {
+            convert_bser_value(
+                hashmap! {
+                    "name".to_string() => Value::from(name),
+                    "content.sha1hex".to_string() => hash,
+                }
+                .into(),
+            )
+        })
+        .collect();
+
+        let (ok, errored) = partition_file_errors(files, |f| f.hash.error());
+        let names = |files: &[NameAndHash]| -> Vec<PathBuf> {
+            files.iter().map(|f| f.name.clone().into_inner()).collect()
+        };
+        assert_eq!(names(&ok), vec![PathBuf::from("a"), PathBuf::from("c")]);
+        assert_eq!(
+            names(&errored),
+            vec![PathBuf::from("b"), PathBuf::from("d")]
+        );
+        assert_eq!(errored[0].hash.error(), Some("Permission denied"));
+    }
+
+    #[test]
+    fn test_get_config_extra() {
+        let response: GetConfigResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "config".to_string() => hashmap! {
+                    "ignore_dirs".to_string() => vec![Value::from("buck-out")].into(),
+                    "settle".to_string() => Value::Integer(20),
+                }
+                .into(),
+            }
+            .into(),
+        );
+        assert_eq!(
+            response.config.ignore_dirs,
+            Some(vec![PathBuf::from("buck-out")])
+        );
+        assert_eq!(response.config.root_files, None);
+        assert_eq!(response.config.extra.len(), 1);
+        assert_eq!(response.config.extra["settle"], Value::Integer(20));
+    }
+
+    #[test]
+    fn test_request_with_command() {
+        let query = QueryRequestCommon {
+            fields: vec!["name"],
+            ..Default::default()
+        };
+        let request = QueryRequest::with_command("fork-query", "/some/root".into(), query);
+        let Value::Array(value) = serialize_to_value(request) else {
+            panic!("expected an array");
+        };
+        assert_eq!(value[0], Value::from("fork-query"));
+        assert_eq!(value[1], Value::from("/some/root"));
+
+        let request = QueryRequest::new("/some/root".into(), QueryRequestCommon::default());
+        assert_eq!(request.0, "query");
+        assert_eq!(
+            WatchProjectRequest::new("/some/root".into()).0,
+            "watch-project"
+        );
+    }
+
+    #[test]
+    fn test_clock_response() {
+        let response: ClockResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "clock".to_string() => Value::from("c:1:2:3:4"),
+            }
+            .into(),
+        );
+        assert!(matches!(
+            response.clock,
+            Clock::Spec(ClockSpec::StringClock(ref c)) if c == "c:1:2:3:4"
+        ));
+
+        let response: ClockResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "clock".to_string() => hashmap! {
+                    "clock".to_string() => Value::from("c:1:2:3:4"),
+                    "scm".to_string() => hashmap! {
+                        "mergebase".to_string() => Value::from("abc123"),
+                        "mergebase-with".to_string() => Value::from("main"),
+                    }
+                    .into(),
+                }
+                .into(),
+            }
+            .into(),
+        );
+        match response.clock {
+            Clock::ScmAware(FatClockData {
+                clock: ClockSpec::StringClock(clock),
+                scm: Some(scm),
+            }) => {
+                assert_eq!(clock, "c:1:2:3:4");
+                assert_eq!(scm.mergebase.as_deref(), Some("abc123"));
+                assert_eq!(scm.mergebase_with.as_deref(), Some("main"));
+            }
+            other => panic!("unexpected clock {:?}", other),
+        }
+    }
+
+    #[test]
+    #[allow(dead_code)]
+    fn test_field_presets() {
+        assert_eq!(
+            SubscribeRequest::cheap_fields(),
+            QueryRequestCommon::cheap_fields()
+        );
+        assert_eq!(
+            SubscribeRequest::content_hash_fields(),
+            QueryRequestCommon::content_hash_fields()
+        );
+        assert_eq!(
+            QueryRequestCommon::content_hash_fields(),
+            vec!["name", "exists", "type", "content.sha1hex"]
+        );
+
+        crate::query_result_type! {
+            struct CheapFile {
+                name: NameField,
+                exists: ExistsField,
+                file_type: FileTypeField,
+            }
+        }
+        assert_eq!(CheapFile::field_list(), QueryRequestCommon::cheap_fields());
+    }
+
+    #[test]
+    fn test_subscribe_request_from_query() {
+        let query = QueryRequestCommon {
+            since: Some(Clock::Spec(ClockSpec::StringClock("c:1:2:3:4".into()))),
+            relative_root: Some("sub".into()),
+            expression: Some(Expr::Exists),
+            fields: vec!["name", "size"],
+            case_sensitive: true,
+            empty_on_fresh_instance: true,
+            suffix: Some(vec!["rs".into()]),
+            ..Default::default()
+        };
+        let sub = SubscribeRequest::from_query(&query);
+        assert!(matches!(
+            sub.since,
+            Some(Clock::Spec(ClockSpec::StringClock(ref c))) if c == "c:1:2:3:4"
+        ));
+        assert_eq!(sub.relative_root, Some(PathBuf::from("sub")));
+        assert!(matches!(sub.expression, Some(Expr::Exists)));
+        assert_eq!(sub.fields, vec!["name", "size"]);
+        assert!(sub.case_sensitive);
+        assert!(sub.empty_on_fresh_instance);
+        assert!(!sub.defer_vcs);
+    }
+
+    #[test]
+    fn test_cookie_dir() {
+        let root = std::env::temp_dir().join(format!(
+            "watchman-client-test-cookie-dir-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut config = WatchmanConfig {
+            ignore_dirs: None,
+            root_files: None,
+            root_restrict_files: None,
+            ignore_vcs: None,
+            extra: HashMap::new(),
+        };
+
+        assert_eq!(config.cookie_dir(&root), root);
+
+        std::fs::create_dir(root.join(".hg")).unwrap();
+        assert_eq!(config.cookie_dir(&root), root.join(".hg"));
+
+        config.ignore_dirs = Some(vec![".hg".into()]);
+        assert_eq!(config.cookie_dir(&root), root);
+
+        config.ignore_dirs = None;
+        config.ignore_vcs = Some(vec![".sl".into()]);
+        assert_eq!(config.cookie_dir(&root), root);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ignore_expression() {
+        let mut config = WatchmanConfig {
+            ignore_dirs: None,
+            root_files: None,
+            root_restrict_files: None,
+            ignore_vcs: None,
+            extra: HashMap::new(),
+        };
+        assert!(config.ignore_expression().is_none());
+
+        config.ignore_dirs = Some(vec![]);
+        assert!(config.ignore_expression().is_none());
+
+        config.ignore_dirs = Some(vec!["buck-out".into(), "node_modules".into()]);
+        assert_eq!(
+            Value::from(config.ignore_expression().unwrap()),
+            vec![
+                "anyof".into(),
+                vec!["dirname".into(), Value::ByteString("buck-out".into())].into(),
+                vec!["dirname".into(), Value::ByteString("node_modules".into())].into(),
+                vec![
+                    "name".into(),
+                    vec![
+                        Value::ByteString("buck-out".into()),
+                        Value::ByteString("node_modules".into())
+                    ]
+                    .into(),
+                    "wholename".into(),
+                ]
+                .into(),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("2023.10.02.00"), Some((2023, 10, 2, 0)));
+        assert_eq!(parse_version("2021.05.31.01"), Some((2021, 5, 31, 1)));
+        assert_eq!(parse_version("4.9.0"), None);
+        assert_eq!(parse_version("2023.10.02.00.1"), None);
+        assert_eq!(parse_version("2023.10.02.00-dev"), None);
+        assert_eq!(parse_version(""), None);
+
+        let old = ServerVersion::parse("2021.05.31.01").unwrap();
+        let new = ServerVersion::parse("2023.10.02.00").unwrap();
+        assert!(old < new);
+        assert!(new < ServerVersion::parse("2023.10.02.01").unwrap());
+        assert_eq!(new.to_string(), "2023.10.02.00");
+
+        let response: GetVersionResponse = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+            }
+            .into(),
+        );
+        assert_eq!(response.server_version(), Some(new));
+    }
+
+    #[test]
+    fn test_decode_response_with_version() {
+        let request = ClockRequest::new("/root".into(), ClockRequestParams::no_sync());
+        let pdu = serde_bser::ser::serialize(
+            Vec::new(),
+            maplit::hashmap! {
+                "version" => "2023.10.02.00",
+                "clock" => "c:1:2",
+            },
+        )
+        .unwrap();
+
+        let (response, version): (ClockResponse, _) =
+            decode_response_with_version(&pdu, &request).unwrap();
+        assert_eq!(version, BserVersion::V2 { capabilities: 0 });
+        assert!(matches!(
+            response.clock.spec(),
+            ClockSpec::StringClock(clock) if clock == "c:1:2"
+        ));
+
+        assert_eq!(
+            BserVersion::of_pdu(b"\x00\x01\x03\x01"),
+            Some(BserVersion::V1)
+        );
+        assert_eq!(BserVersion::of_pdu(b"{}"), None);
+
+        for pdu in [&b"{}"[..], &b""[..], &b"\x00\x03\x00"[..]] {
+            let err = decode_response_with_version::<_, ClockResponse>(pdu, &request).unwrap_err();
+            assert!(matches!(err, crate::Error::Deserialize { .. }), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_decode_response_timed() {
+        let pdu = serde_bser::ser::serialize(
+            Vec::new(),
+            maplit::hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "clock".to_string() => Value::from("c:1:2"),
+                "files".to_string() => Value::Array(vec![Value::from("foo")]),
+            },
+        )
+        .unwrap();
+        let (result, elapsed) = decode_response_timed::<QueryResult<NameOnly>>(&pdu);
+        assert!(elapsed >= std::time::Duration::ZERO);
+        assert_eq!(result.unwrap().into_files().len(), 1);
+
+        let pdu = serde_bser::ser::serialize(
+            Vec::new(),
+            maplit::hashmap! {
+                "error" => "unable to resolve root /bogus",
+            },
+        )
+        .unwrap();
+        let (result, _) = decode_response_timed::<QueryResult<NameOnly>>(&pdu);
+        assert!(matches!(
+            result,
+            Err(crate::Error::WatchmanResponseError { message }) if message.contains("/bogus")
+        ));
+    }
+
+    #[test]
+    fn test_server_version_supports() {
+        let release = |version| ServerVersion::parse(version).unwrap();
+
+        // The first date based release, per docs/release-notes.md, already
+        // supports source control aware queries but not settling
+        let first_dated = release("2020.07.13.00");
+        assert!(first_dated.supports(Feature::ScmSince));
+        assert!(!first_dated.supports(Feature::SettlePeriod));
+
+        // The releases either side of the one that added settling
+        assert!(!release("2021.11.08.00").supports(Feature::SettlePeriod));
+        assert!(release("2021.11.15.00").supports(Feature::SettlePeriod));
+        assert!(release("2021.11.15.01").supports(Feature::SettlePeriod));
+        assert!(release("2023.10.02.00").supports(Feature::SettlePeriod));
+
+        // Semver style releases, which `parse` rejects, compare below any
+        // date based release when expressed via their components
+        let semver = |major, minor| ServerVersion {
+            year: major,
+            month: minor,
+            day: 0,
+            patch: 0,
+        };
+        assert!(!semver(4, 8).supports(Feature::ScmSince));
+        assert!(semver(4, 9).supports(Feature::ScmSince));
+        assert!(!semver(4, 9).supports(Feature::SettlePeriod));
+        assert!(semver(4, 9) < first_dated);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_to_cli_command() {
+        let req = ClockRequest::new(
+            PathBuf::from("/root"),
+            ClockRequestParams {
+                sync_timeout: SyncTimeout::Duration(std::time::Duration::from_millis(100)),
+            },
+        );
+        assert_eq!(
+            to_cli_command(&req),
+            r#"watchman -- clock /root '{"sync_timeout":100}'"#
+        );
+
+        let req = ClockRequest::new(
+            PathBuf::from("/path with/it's"),
+            ClockRequestParams {
+                sync_timeout: SyncTimeout::DisableCookie,
+            },
+        );
+        assert_eq!(
+            to_cli_command(&req),
+            r#"watchman -- clock '/path with/it'\''s' '{}'"#
+        );
+    }
+
+    #[test]
+    fn test_resolve_symlink() {
+        let root = Path::new("/repo");
+        assert_eq!(
+            resolve_symlink(Path::new("a/b/link"), Path::new("target.txt"), root),
+            PathBuf::from("/repo/a/b/target.txt")
+        );
+        assert_eq!(
+            resolve_symlink(Path::new("link"), Path::new("../sibling/c"), root),
+            PathBuf::from("/repo/../sibling/c")
+        );
+        assert_eq!(
+            resolve_symlink(Path::new("a/link"), Path::new("/etc/hosts"), root),
+            PathBuf::from("/etc/hosts")
+        );
+    }
+
+    #[test]
+    fn test_canonical_root() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(
+            canonical_root(manifest_dir.join("src").join("..")).unwrap(),
+            std::fs::canonicalize(manifest_dir).unwrap()
+        );
+
+        let err = canonical_root(manifest_dir.join("Cargo.toml")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        assert!(canonical_root(manifest_dir.join("does-not-exist")).is_err());
+    }
+
+    #[test]
+    fn test_write_request() {
+        let paths: Vec<PathGeneratorElement> = (0..20_000)
+            .map(|i| PathGeneratorElement::RecursivePath(format!("dir/{}", i).into()))
+            .collect();
+        let request = QueryRequest(
+            "query",
+            PathBuf::from("/some/root"),
+            QueryRequestCommon {
+                path: Some(paths),
+                ..Default::default()
+            },
+        );
+
+        let mut buf = vec![];
+        write_request(&mut buf, &request).unwrap();
+
+        let decoded: Value = serde_bser::from_slice(&buf).unwrap();
+        match decoded {
+            Value::Array(items) => {
+                assert_eq!(items.len(), 3);
+                assert_eq!(items[0], Value::from("query"));
+                match &items[2] {
+                    Value::Object(query) => match &query["path"] {
+                        Value::Array(path) => {
+                            assert_eq!(path.len(), 20_000);
+                            assert_eq!(path[19_999], Value::from("dir/19999"));
+                        }
+                        other => panic!("unexpected path {:?}", other),
+                    },
+                    other => panic!("unexpected query {:?}", other),
+                }
+            }
+            other => panic!("unexpected request {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ensure_valid() {
+        let query = QueryRequestCommon::default();
+        let err = query.ensure_valid().unwrap_err();
+        assert!(err.contains("no fields"), "{}", err);
+
+        // The server doesn't require the `name` field
+        let query = QueryRequestCommon {
+            fields: vec!["size"],
+            ..Default::default()
+        };
+        assert_eq!(query.ensure_valid(), Ok(()));
+        let err = query.ensure_requests_name().unwrap_err();
+        assert!(err.contains("`name`"), "{}", err);
+
+        let query = QueryRequestCommon {
+            fields: vec!["name", "size"],
+            ..Default::default()
+        };
+        assert_eq!(query.ensure_valid(), Ok(()));
+        assert_eq!(query.ensure_requests_name(), Ok(()));
+
+        let query = QueryRequestCommon {
+            fields: vec!["name"],
+            path: Some(vec![
+                PathGeneratorElement::constrained("src", 2),
+                PathGeneratorElement::ConstrainedDepth {
+                    path: "docs".into(),
+                    depth: -1,
+                },
+            ]),
+            ..Default::default()
+        };
+        let err = query.ensure_valid().unwrap_err();
+        assert!(err.contains("docs"), "{}", err);
+    }
+
+    #[test]
+    fn test_path_generator_constrained() {
+        // The depth is unsigned, so a negative depth is rejected at
+        // compile time rather than being sent to the server
+        let element = PathGeneratorElement::constrained("src", 3);
+        assert_eq!(
+            serialize_to_value(element),
+            hashmap! {
+                "path".to_string() => Value::from("src"),
+                "depth".to_string() => Value::Integer(3),
+            }
+            .into()
+        );
+
+        let query = QueryRequestCommon {
+            fields: vec!["name"],
+            path: Some(vec![PathGeneratorElement::constrained("src", u32::MAX)]),
+            ..Default::default()
+        };
+        assert_eq!(query.ensure_valid(), Ok(()));
+    }
+
+    #[test]
+    fn test_chunk_paths() {
+        let query = QueryRequestCommon {
+            path: Some(
+                (0..2500)
+                    .map(|i| PathGeneratorElement::RecursivePath(format!("{}", i).into()))
+                    .collect(),
+            ),
+            expression: Some(Expr::Exists),
+            ..Default::default()
+        };
+
+        let batches = query.chunk_paths(1000);
+        let sizes: Vec<usize> = batches
+            .iter()
+            .map(|batch| batch.path.as_ref().unwrap().len())
+            .collect();
+        assert_eq!(sizes, vec![1000, 1000, 500]);
+        for (i, batch) in batches.iter().enumerate() {
+            assert!(matches!(batch.expression, Some(Expr::Exists)));
+            match &batch.path.as_ref().unwrap()[0] {
+                PathGeneratorElement::RecursivePath(path) => {
+                    assert_eq!(*path, PathBuf::from(format!("{}", i * 1000)))
+                }
+                other => panic!("unexpected path {:?}", other),
+            }
+        }
+
+        let batches = QueryRequestCommon::default().chunk_paths(1000);
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].path.is_none());
+    }
+
+    #[test]
+    fn test_subscription_root() {
+        let result: QueryResult<NameOnly> = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "clock".to_string() => Value::from("c:0:1"),
+                "subscription".to_string() => Value::from("sub-1"),
+                "unilateral".to_string() => Value::Bool(true),
+                "root".to_string() => Value::from("/some/root"),
+                "files".to_string() => vec![Value::from("foo")].into(),
+            }
+            .into(),
+        );
+        assert_eq!(result.root, Some(PathBuf::from("/some/root")));
+        assert_eq!(result.into_files().len(), 1);
+
+        let result: QueryResult<NameOnly> = convert_bser_value(
+            hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "clock".to_string() => Value::from("c:0:1"),
+            }
+            .into(),
+        );
+        assert_eq!(result.root, None);
+    }
+
+    #[test]
+    fn test_delta_source() {
+        let saved_state_clock = |commit: &str| -> Clock {
+            convert_bser_value(
+                hashmap! {
+                    "clock".to_string() => Value::from("c:1:2:3:4"),
+                    "scm".to_string() => hashmap! {
+                        "mergebase".to_string() => Value::from("abc123"),
+                        "mergebase-with".to_string() => Value::from("main"),
+                        "saved-state".to_string() => hashmap! {
+                            "storage".to_string() => Value::from("local"),
+                            "config".to_string() => Value::from(hashmap! {}),
+                            "commit-id".to_string() => Value::from(commit),
+                        }
+                        .into(),
+                    }
+                    .into(),
+                }
+                .into(),
+            )
+        };
+
+        let mut result = query_result::<NameOnly>(Some(vec![]));
+        result.is_fresh_instance = true;
+        assert_eq!(result.delta_source(), DeltaSource::FreshInstance);
+
+        let mut result = query_result::<NameOnly>(Some(vec![]));
+        assert_eq!(result.delta_source(), DeltaSource::Since);
+
+        result.clock = saved_state_clock("abc123");
+        result.saved_state_info = Some(
+            hashmap! {
+                "commit-id".to_string() => Value::from("abc123"),
+            }
+            .into(),
+        );
+        assert_eq!(result.delta_source(), DeltaSource::SavedState);
+
+        // The merge base changed but no saved state was found
+        result.clock = saved_state_clock("");
+        result.saved_state_info = Some(
+            hashmap! {
+                "error".to_string() => Value::from("No suitable saved state found"),
+            }
+            .into(),
+        );
+        assert_eq!(result.delta_source(), DeltaSource::Since);
+
+        // The merge base didn't change, so no saved state info is reported
+        result.clock = saved_state_clock("abc123");
+        result.saved_state_info = None;
+        assert_eq!(result.delta_source(), DeltaSource::Since);
+    }
+
+    #[test]
+    fn test_sort_by_mtime() {
+        crate::query_result_type! {
+            struct NameAndTimes {
+                name: NameField,
+                mtime: MTimeField,
+                ctime: CTimeField,
+            }
+        }
+
+        let files: Vec<NameAndTimes> = [("c", 30, 1), ("a", 10, 3), ("b", 20, 2), ("d", 10, 4)]
+            .iter()
+            .map(|(name, mtime, ctime)| {
+                convert_bser_value(
+                    hashmap! {
+                        "name".to_string() => Value::from(*name),
+                        "mtime".to_string() => Value::Integer(*mtime),
+                        "ctime".to_string() => Value::Integer(*ctime),
+                    }
+                    .into(),
+                )
+            })
+            .collect();
+        let names = |result: &QueryResult<NameAndTimes>| -> Vec<String> {
+            result
+                .files
+                .as_ref()
+                .unwrap()
+                .iter()
+                .map(|f| f.name.to_string_lossy().into_owned())
+                .collect()
+        };
+
+        let mut result = query_result(Some(files));
+        result.sort_by_mtime(|f| *f.mtime);
+        assert_eq!(names(&result), vec!["a", "d", "b", "c"]);
+
+        result.sort_by_ctime(|f| *f.ctime);
+        assert_eq!(names(&result), vec!["c", "b", "a", "d"]);
+    }
+
+    #[test]
+    fn test_sort_by_name() {
+        let mut result = query_result(Some(
+            ["src/pdu.rs", "Cargo.toml", "src/lib.rs", "src/bin/tool.rs"]
+                .iter()
+                .map(|name| NameOnly::from(PathBuf::from(name)))
+                .collect(),
+        ));
+        result.sort_by_name(|f| &f.name);
+        let names: Vec<PathBuf> = result
+            .into_files()
+            .into_iter()
+            .map(|f| f.name.into_inner())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                PathBuf::from("Cargo.toml"),
+                PathBuf::from("src/bin/tool.rs"),
+                PathBuf::from("src/lib.rs"),
+                PathBuf::from("src/pdu.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_needs_full_resync() {
+        let mut result = query_result::<NameOnly>(Some(vec![]));
+        assert!(!result.needs_full_resync(true));
+        assert!(!result.needs_full_resync(false));
+
+        result.is_fresh_instance = true;
+        assert!(result.needs_full_resync(true));
+        assert!(!result.needs_full_resync(false));
+    }
+
+    #[test]
+    fn test_state_metadata_as() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct UpdateMetadata {
+            rev: String,
+            distance: i64,
+        }
+
+        let mut result = query_result::<NameOnly>(None);
+        assert!(result.state_metadata_as::<UpdateMetadata>().is_none());
+
+        result.state_enter = Some("hg.update".into());
+        result.state_metadata = Some(
+            hashmap! {
+                "rev".to_string() => Value::from("abc123"),
+                "distance".to_string() => Value::Integer(2),
+            }
+            .into(),
+        );
+        assert_eq!(
+            result
+                .state_metadata_as::<UpdateMetadata>()
+                .unwrap()
+                .unwrap(),
+            UpdateMetadata {
+                rev: "abc123".into(),
+                distance: 2,
+            }
+        );
+
+        result.state_metadata = Some("bogus".into());
+        assert!(
+            result
+                .state_metadata_as::<UpdateMetadata>()
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_dedup_by() {
+        crate::query_result_type! {
+            struct NameAndInode {
+                name: NameField,
+                ino: InodeNumberField,
+            }
+        }
+
+        let files: Vec<NameAndInode> = [("a", 1), ("b", 2), ("a-link", 1), ("c", 3), ("b-link", 2)]
+            .iter()
+            .map(|(name, ino)| {
+                convert_bser_value(
+                    hashmap! {
+                        "name".to_string() => Value::from(*name),
+                        "ino".to_string() => Value::Integer(*ino),
+                    }
+                    .into(),
+                )
+            })
+            .collect();
+
+        let mut result = query_result(Some(files));
+        result.dedup_by(|f| *f.ino);
+        let names: Vec<PathBuf> = result
+            .into_files()
+            .into_iter()
+            .map(|f| f.name.into_inner())
+            .collect();
+        assert_eq!(
+            names,
+            vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")]
+        );
+    }
+
+    #[test]
+    fn test_name_only() {
+        assert_eq!(NameOnly::FIELDS, NameOnly::field_list().as_slice());
+        let files: Vec<NameOnly> = convert_bser_value(
+            vec![
+                Value::ByteString("foo.txt".into()),
+                Value::Utf8String("bar/baz.txt".into()),
+            ]
+            .into(),
+        );
+        let names: Vec<&Path> = files.iter().map(|f| f.name.as_path()).collect();
+        assert_eq!(names, vec![Path::new("foo.txt"), Path::new("bar/baz.txt")]);
+    }
+
+    #[test]
+    fn test_name_and_type() {
+        assert_eq!(NameAndType::FIELDS, NameAndType::field_list().as_slice());
+        let files: Vec<NameAndType> = convert_bser_value(
+            [("src", "d"), ("src/lib.rs", "f"), ("latest", "l")]
+                .iter()
+                .map(|(name, file_type)| {
+                    Value::from(hashmap! {
+                        "name".to_string() => Value::ByteString((*name).into()),
+                        "type".to_string() => Value::from(*file_type),
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into(),
+        );
+        assert_eq!(
+            files,
+            vec![
+                NameAndType {
+                    name: "src".into(),
+                    file_type: FileType::Directory,
+                },
+                NameAndType {
+                    name: "src/lib.rs".into(),
+                    file_type: FileType::Regular,
+                },
+                NameAndType {
+                    name: "latest".into(),
+                    file_type: FileType::Symlink,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subscription_event() {
+        let event = |fields: HashMap<String, Value>| -> SubscriptionEvent<NameOnly> {
+            let mut pdu: HashMap<String, Value> = hashmap! {
+                "version".to_string() => Value::from("2023.10.02.00"),
+                "unilateral".to_string() => Value::Bool(true),
+                "subscription".to_string() => Value::from("sub"),
+            };
+            pdu.extend(fields);
+            convert_bser_value(pdu.into())
+        };
+
+        match event(hashmap! {
+            "clock".to_string() => Value::from("c:1:2"),
+            "is_fresh_instance".to_string() => Value::Bool(true),
+            "files".to_string() => Value::Array(vec![Value::ByteString("foo".into())]),
+        }) {
+            SubscriptionEvent::Files(result) => {
+                assert!(result.is_fresh_instance);
+                let files = result.into_files();
+                assert_eq!(files.len(), 1);
+                assert_eq!(*files[0].name, PathBuf::from("foo"));
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        match event(hashmap! {
+            "clock".to_string() => Value::from("c:1:3"),
+            "state-enter".to_string() => Value::from("hg.update"),
+            "metadata".to_string() => Value::from(hashmap! {
+                "rev".to_string() => Value::from("abc"),
+            }),
+        }) {
+            SubscriptionEvent::StateEnter { name, metadata } => {
+                assert_eq!(name, "hg.update");
+                assert!(metadata.is_some());
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        match event(hashmap! {
+            "clock".to_string() => Value::from("c:1:4"),
+            "state-leave".to_string() => Value::from("hg.update"),
+        }) {
+            SubscriptionEvent::StateLeave { name, metadata } => {
+                assert_eq!(name, "hg.update");
+                assert!(metadata.is_none());
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        match event(hashmap! {
+            "canceled".to_string() => Value::Bool(true),
+            "root".to_string() => Value::from("/repo"),
+        }) {
+            SubscriptionEvent::Canceled { root } => {
+                assert_eq!(root, Some(PathBuf::from("/repo")));
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_query_result_template() {
+        crate::query_result_type! {
+            struct NameAndSize {
+                name: NameField,
+                size: SizeField,
+            }
+        }
+
+        // The serializer never produces the template encoding that the
+        // server uses for lists of files, so the PDU is encoded by hand
+        fn string(s: &str) -> Vec<u8> {
+            let mut buf = vec![0x02, 0x03, s.len() as u8];
+            buf.extend_from_slice(s.as_bytes());
+            buf
+        }
+        fn int(i: i8) -> Vec<u8> {
+            vec![0x03, i as u8]
+        }
+
+        let mut payload = vec![0x01];
+        payload.extend(int(4));
+        payload.extend(string("version"));
+        payload.extend(string("2023.10.02.00"));
+        payload.extend(string("is_fresh_instance"));
+        payload.push(0x08);
+        payload.extend(string("clock"));
+        payload.extend(string("c:1:2"));
+        payload.extend(string("files"));
+        // A template with the keys `name` and `size` and two rows
+        payload.push(0x0b);
+        payload.push(0x00);
+        payload.extend(int(2));
+        payload.extend(string("name"));
+        payload.extend(string("size"));
+        payload.extend(int(2));
+        payload.extend(string("foo.txt"));
+        payload.extend(int(10));
+        payload.extend(string("bar/baz.txt"));
+        payload.extend(int(20));
+
+        let mut pdu = vec![0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x05];
+        pdu.extend((payload.len() as u32).to_le_bytes());
+        pdu.extend(payload);
+
+        let result: QueryResult<NameAndSize> = bunser(&pdu).unwrap();
+        assert!(result.is_fresh_instance);
+        let files: Vec<(PathBuf, u64)> = result
+            .into_files()
+            .into_iter()
+            .map(|f| (f.name.into_inner(), f.size.into_inner()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (PathBuf::from("foo.txt"), 10),
+                (PathBuf::from("bar/baz.txt"), 20)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_owner() {
+        crate::query_result_type! {
+            struct NameAndOwner {
+                name: NameField,
+                uid: OwnerUidField,
+                gid: OwnerGidField,
+            }
+        }
+
+        let file: NameAndOwner = convert_bser_value(
+            hashmap! {
+                "name".to_string() => Value::from("foo"),
+                "uid".to_string() => Value::Integer(1000),
+                "gid".to_string() => Value::Integer(100),
+            }
+            .into(),
+        );
+        assert_eq!(*file.name, PathBuf::from("foo"));
+        assert_eq!(
+            FileOwner::from_fields(&file.uid, &file.gid),
+            FileOwner {
+                uid: 1000,
+                gid: 100
+            }
+        );
+
+        #[derive(Deserialize, Debug)]
+        struct HandWritten {
+            name: PathBuf,
+            #[serde(flatten)]
+            owner: FileOwner,
+        }
+        let file: HandWritten = convert_bser_value(
+            hashmap! {
+                "name".to_string() => Value::from("bar"),
+                "uid".to_string() => Value::Integer(0),
+                "gid".to_string() => Value::Integer(0),
+            }
+            .into(),
+        );
+        assert_eq!(file.name, PathBuf::from("bar"));
+        assert_eq!(file.owner, FileOwner { uid: 0, gid: 0 });
+    }
+
+    #[test]
+    fn test_into_name_map() {
+        crate::query_result_type! {
+            struct NameAndSize {
+                name: NameField,
+                size: SizeField,
+            }
+        }
+
+        let files: Vec<NameAndSize> = [("a", 1), ("b", 2), ("a", 3)]
+            .iter()
+            .map(|(name, size)| {
+                convert_bser_value(
+                    hashmap! {
+                        "name".to_string() => Value::from(*name),
+                        "size".to_string() => Value::Integer(*size),
+                    }
+                    .into(),
+                )
+            })
+            .collect();
+
+        let map = query_result(Some(files)).into_name_map(|f| f.name.clone().into_inner());
+        assert_eq!(map.len(), 2);
+        assert_eq!(*map[Path::new("a")].size, 3);
+        assert_eq!(*map[Path::new("b")].size, 2);
+        assert!(!map.contains_key(Path::new("c")));
+
+        assert!(
+            query_result::<NameOnly>(None)
+                .into_name_map(|f| f.name.clone().into_inner())
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut result = query_result(Some(vec![
+            NameOnly::from(PathBuf::from("foo")),
+            NameOnly::from(PathBuf::from("bar")),
+        ]));
+        result.is_fresh_instance = true;
+
+        let summary = result.summary();
+        assert_eq!(summary.file_count, 2);
+        assert!(summary.is_fresh_instance);
+        assert!(matches!(
+            summary.clock,
+            Clock::Spec(ClockSpec::StringClock(ref c)) if c == "c:0:0"
+        ));
+
+        assert_eq!(query_result::<NameOnly>(None).summary().file_count, 0);
+    }
+
+    #[test]
+    fn test_exclude_cookies() {
+        let mut query = QueryRequestCommon {
+            expression: Some(Expr::Exists),
+            ..Default::default()
+        };
+        query.exclude_cookies();
+        match query.expression.unwrap() {
+            Expr::All(terms) => {
+                assert_eq!(terms.len(), 2);
+                match &terms[0] {
+                    Expr::Not(term) => match term.as_ref() {
+                        Expr::Match(term) => {
+                            assert_eq!(term.glob, ".watchman-cookie-*");
+                            assert!(!term.wholename);
+                            assert!(term.include_dot_files);
+                        }
+                        other => panic!("unexpected term {:?}", other),
+                    },
+                    other => panic!("unexpected term {:?}", other),
+                }
+                assert!(matches!(terms[1], Expr::Exists));
+            }
+            other => panic!("unexpected expression {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_exclude_vcs_dirs() {
+        let mut query = QueryRequestCommon {
+            expression: Some(Expr::Exists),
+            ..Default::default()
+        };
+        query.exclude_vcs_dirs();
+        let dirname =
+            |dir: &str| -> Value { vec!["dirname".into(), Value::ByteString(dir.into())].into() };
+        assert_eq!(
+            Value::from(query.expression.unwrap()),
+            vec![
+                "allof".into(),
+                vec![
+                    "not".into(),
+                    vec![
+                        "anyof".into(),
+                        dirname(".git"),
+                        dirname(".hg"),
+                        dirname(".svn"),
+                        vec![
+                            "name".into(),
+                            vec![
+                                Value::ByteString(".git".into()),
+                                Value::ByteString(".hg".into()),
+                                Value::ByteString(".svn".into()),
+                            ]
+                            .into(),
+                            "wholename".into(),
+                        ]
+                        .into(),
+                    ]
+                    .into(),
+                ]
+                .into(),
+                "exists".into(),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_subtree_excluding() {
+        let query = QueryRequestCommon::subtree_excluding(
+            "src".into(),
+            vec![PathBuf::from("src/gen"), PathBuf::from("src/out")],
+        );
+        match query.path.as_deref() {
+            Some([PathGeneratorElement::RecursivePath(path)]) => {
+                assert_eq!(*path, PathBuf::from("src"))
+            }
+            other => panic!("unexpected path {:?}", other),
+        }
+        assert_eq!(
+            Value::from(query.expression.unwrap()),
+            vec![
+                "not".into(),
+                vec![
+                    "anyof".into(),
+                    vec!["dirname".into(), Value::ByteString("src/gen".into())].into(),
+                    vec!["dirname".into(), Value::ByteString("src/out".into())].into(),
+                    vec![
+                        "name".into(),
+                        vec![
+                            Value::ByteString("src/gen".into()),
+                            Value::ByteString("src/out".into())
+                        ]
+                        .into(),
+                        "wholename".into(),
+                    ]
+                    .into(),
+                ]
+                .into(),
+            ]
+            .into()
+        );
+
+        let query = QueryRequestCommon::subtree_excluding("src".into(), vec![]);
+        assert!(query.expression.is_none());
+    }
+
+    #[test]
+    fn test_with_suffixes() {
+        let query = QueryRequestCommon::default().with_suffixes_ci(vec!["php".into(), "js".into()]);
+        assert!(query.suffix.is_none());
+        assert_eq!(
+            Value::from(query.expression.unwrap()),
+            vec![
+                "suffix".into(),
+                vec![
+                    Value::ByteString("php".into()),
+                    Value::ByteString("js".into())
+                ]
+                .into(),
+            ]
+            .into()
+        );
+
+        let query = QueryRequestCommon {
+            expression: Some(Expr::Exists),
+            ..Default::default()
+        }
+        .with_suffixes_cs(vec!["PHP".into(), "a*b?[c]".into()]);
+        assert!(query.suffix.is_none());
+        // The case sensitivity of the rest of the query is left alone
+        assert!(!query.case_sensitive);
+        match query.expression.unwrap() {
+            Expr::All(terms) => match terms.as_slice() {
+                [Expr::Any(suffixes), Expr::Exists] => match suffixes.as_slice() {
+                    [Expr::Match(term), Expr::Match(escaped)] => {
+                        assert_eq!(term.glob, "*.PHP");
+                        assert!(!term.wholename);
+                        assert!(term.include_dot_files);
+                        assert!(!escaped.no_escape);
+                        assert_eq!(escaped.glob, r"*.a\*b\?\[c\]");
+                    }
+                    terms => panic!("unexpected suffix terms {:?}", terms),
+                },
+                terms => panic!("unexpected terms {:?}", terms),
+            },
+            expr => panic!("unexpected expression {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn test_validate_relative_root() {
+        let root = Path::new("/home/user/repo");
+        let query = |relative_root: Option<&str>| QueryRequestCommon {
+            relative_root: relative_root.map(PathBuf::from),
+            ..Default::default()
+        };
+
+        assert_eq!(query(None).validate_relative_root(root), Ok(()));
+        assert_eq!(query(Some("src/lib")).validate_relative_root(root), Ok(()));
+        assert_eq!(
+            query(Some("src/../lib")).validate_relative_root(root),
+            Ok(())
+        );
+        assert_eq!(
+            query(Some("/home/user/repo/src")).validate_relative_root(root),
+            Ok(())
+        );
+
+        assert!(
+            query(Some("/home/user/other"))
+                .validate_relative_root(root)
+                .is_err()
+        );
+        assert!(
+            query(Some("../other"))
+                .validate_relative_root(root)
+                .is_err()
+        );
+        assert!(
+            query(Some("src/../../other"))
+                .validate_relative_root(root)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_clock_spec() {
+        let mut result = query_result::<NameOnly>(None);
+        result.clock = Clock::Spec(ClockSpec::StringClock("c:1:2".into()));
+        assert!(matches!(result.clock_spec(), ClockSpec::StringClock(clock) if clock == "c:1:2"));
+
+        result.clock = Clock::ScmAware(FatClockData {
+            clock: ClockSpec::StringClock("c:1:3".into()),
+            scm: Some(ScmAwareClockData::with_mergebase_with("main")),
+        });
+        assert!(matches!(result.clock_spec(), ClockSpec::StringClock(clock) if clock == "c:1:3"));
+    }
+
+    #[test]
+    fn test_path_project_relative() {
+        let elements = || {
+            vec![
+                PathGeneratorElement::RecursivePath("sub/dir/a".into()),
+                PathGeneratorElement::constrained("sub/dir/b/c", 1),
+                PathGeneratorElement::RecursivePath("sub/dir".into()),
+            ]
+        };
+
+        let mut query = QueryRequestCommon::default();
+        query
+            .path_project_relative(elements(), Some(Path::new("sub/dir")))
+            .unwrap();
+        assert_eq!(
+            serialize_to_value(query.path),
+            vec![
+                Value::from("a"),
+                hashmap! {
+                    "path".to_string() => Value::from("b/c"),
+                    "depth".to_string() => Value::Integer(1),
+                }
+                .into(),
+                Value::from(""),
+            ]
+            .into()
+        );
+
+        let mut query = QueryRequestCommon::default();
+        query.path_project_relative(elements(), None).unwrap();
+        assert_eq!(
+            serialize_to_value(query.path),
+            serialize_to_value(Some(elements()))
+        );
+
+        let mut query = QueryRequestCommon::default();
+        let err = query
+            .path_project_relative(
+                vec![PathGeneratorElement::RecursivePath("other/dir".into())],
+                Some(Path::new("sub/dir")),
+            )
+            .unwrap_err();
+        assert!(err.contains("other/dir"), "{}", err);
+        assert!(query.path.is_none());
+    }
+
+    #[test]
+    fn test_since_age() {
+        let now = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_500);
+        let mut query = QueryRequestCommon::default();
+        query.since_age_at(3600, now);
+        assert!(matches!(
+            query.since,
+            Some(Clock::Spec(ClockSpec::UnixTimestamp(1_699_996_400)))
+        ));
+
+        query.since_age(60);
+        match query.since {
+            Some(Clock::Spec(ClockSpec::UnixTimestamp(ts))) => assert!(ts > 1_699_996_400),
+            other => panic!("unexpected since {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_continue_from() {
+        let mut prev = query_result::<NameOnly>(None);
+        prev.clock = Clock::Spec(ClockSpec::StringClock("c:1:2".into()));
+        let mut query = QueryRequestCommon::default();
+        query.continue_from(&prev);
+        assert_eq!(serialize_to_value(query.since), Value::from("c:1:2"));
+
+        prev.clock = Clock::ScmAware(FatClockData {
+            clock: ClockSpec::StringClock("c:1:3".into()),
+            scm: Some(ScmAwareClockData {
+                mergebase: Some("abc123".into()),
+                mergebase_with: Some("main".into()),
+                saved_state: None,
+            }),
+        });
+        let mut query = QueryRequestCommon {
+            since: Some(Clock::Spec(ClockSpec::null())),
+            ..Default::default()
+        };
+        query.continue_from(&prev);
+        assert_eq!(
+            serialize_to_value(query.since),
+            hashmap! {
+                "clock".to_string() => Value::from("c:1:3"),
+                "scm".to_string() => hashmap! {
+                    "mergebase".to_string() => Value::from("abc123"),
+                    "mergebase-with".to_string() => Value::from("main"),
+                }
+                .into(),
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_file_size() {
+        let size: FileSize = convert_bser_value(Value::Integer(1024));
+        assert_eq!(size, FileSize(1024));
+        assert_eq!(*size, 1024);
+
+        let size: FileSize = convert_bser_value(Value::Integer(i64::MAX));
+        assert_eq!(u64::from(size), i64::MAX as u64);
+
+        let binary = serde_bser::ser::serialize(Vec::new(), Value::Integer(-1)).unwrap();
+        match bunser::<FileSize>(&binary) {
+            Err(crate::Error::Deserialize { source, .. }) => assert!(
+                source
+                    .to_string()
+                    .contains("file size must not be negative, but got -1"),
+                "{}",
+                source
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_assert_safe_cookieless() {
+        let cookieless = QueryRequestCommon {
+            sync_timeout: SyncTimeout::DisableCookie,
+            ..Default::default()
+        };
+        let mut sync_state = SyncState::default();
+
+        // Queries that synchronize are always safe
+        assert_eq!(
+            QueryRequestCommon::default().assert_safe_cookieless(&sync_state),
+            Ok(())
+        );
+
+        // Disabling the cookie before synchronizing is not
+        assert!(cookieless.assert_safe_cookieless(&sync_state).is_err());
+        sync_state.record_clock(&ClockRequestParams::no_sync());
+        sync_state.record_query(&cookieless);
+        assert!(cookieless.assert_safe_cookieless(&sync_state).is_err());
+
+        // But it is once a synchronized request has completed
+        sync_state.record_clock(&ClockRequestParams::synced(std::time::Duration::from_secs(
+            10,
+        )));
+        assert_eq!(cookieless.assert_safe_cookieless(&sync_state), Ok(()));
+
+        let mut sync_state = SyncState::default();
+        sync_state.record_query(&QueryRequestCommon::default());
+        assert_eq!(cookieless.assert_safe_cookieless(&sync_state), Ok(()));
+    }
+
+    #[test]
+    fn test_exclude() {
+        let node_modules = || {
+            Expr::DirName(crate::expr::DirNameTerm {
+                path: "node_modules".into(),
+                depth: None,
+            })
+        };
+        let not_node_modules: Value = vec![
+            "not".into(),
+            vec!["dirname".into(), Value::ByteString("node_modules".into())].into(),
+        ]
+        .into();
+
+        let mut query = QueryRequestCommon::default();
+        query.exclude(node_modules());
+        assert_eq!(
+            Value::from(query.expression.unwrap()),
+            not_node_modules.clone()
+        );
+
+        let mut query = QueryRequestCommon {
+            expression: Some(Expr::Exists),
+            ..Default::default()
+        };
+        query.exclude(node_modules());
+        assert_eq!(
+            Value::from(query.expression.unwrap()),
+            vec!["allof".into(), not_node_modules, "exists".into()].into()
+        );
+    }
 }